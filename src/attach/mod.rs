@@ -0,0 +1,359 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Largest single file that can be attached
+pub const MAX_FILE_SIZE: u64 = 256 * 1024;
+/// Largest combined size of the attachments on one prompt
+pub const MAX_TOTAL_SIZE: u64 = 1024 * 1024;
+/// Largest image that can be attached
+pub const MAX_IMAGE_SIZE: u64 = 20 * 1024 * 1024;
+// Bytes inspected when looking for NUL bytes
+const BINARY_SNIFF_LEN: usize = 8000;
+
+#[derive(Clone)]
+pub struct Attachment {
+    pub path: String,
+    pub content: String,
+}
+
+/// Read the files matched by each path, directory or glob pattern.
+/// Files named explicitly must be text within the size limit; binary or
+/// oversized files found in directories and globs are skipped and returned
+/// as the second element with the reason.
+pub fn collect(patterns: &[String]) -> Result<(Vec<Attachment>, Vec<String>)> {
+    let mut attachments: Vec<Attachment> = vec![];
+    let mut skipped: Vec<String> = vec![];
+    for pattern in patterns {
+        let explicit = !is_glob(pattern) && !Path::new(pattern).is_dir();
+        for path in expand(pattern)? {
+            let label = path.to_string_lossy().to_string();
+            if attachments.iter().any(|a| a.path == label) {
+                continue;
+            }
+            match read(&path) {
+                Ok(content) => attachments.push(Attachment {
+                    path: label,
+                    content,
+                }),
+                Err(e) if explicit => bail!("Cannot attach \"{}\" -> {}", label, e),
+                Err(e) => skipped.push(format!("{} ({})", label, e)),
+            }
+        }
+    }
+    let total: usize = attachments.iter().map(|a| a.content.len()).sum();
+    if total as u64 > MAX_TOTAL_SIZE {
+        bail!(
+            "Attachments total {} KiB, limit is {} KiB",
+            total / 1024,
+            MAX_TOTAL_SIZE / 1024
+        );
+    }
+    Ok((attachments, skipped))
+}
+
+/// Prompt with each attachment prepended as a fenced block labelled with its
+/// language and path
+pub fn compose(prompt: &str, attachments: &[Attachment]) -> String {
+    if attachments.is_empty() {
+        return prompt.to_string();
+    }
+    let mut composed = String::new();
+    for a in attachments {
+        composed.push_str(&fenced(&a.path, &a.path, &a.content));
+        composed.push_str("\n\n");
+    }
+    composed.push_str(prompt);
+    composed
+}
+
+/// `content` as a fenced block labelled with `label` and the language of `path`
+pub fn fenced(label: &str, path: &str, content: &str) -> String {
+    // Longer fence than any backtick run inside, so the block can't end early
+    let fence = "`".repeat(longest_backtick_run(content).max(2) + 1);
+    format!(
+        "{}{} {}\n{}\n{}",
+        fence,
+        language(path),
+        label,
+        content.trim_end_matches('\n'),
+        fence
+    )
+}
+
+/// Absolute paths of the given images, once each is known to be a readable
+/// PNG or JPEG, so saved conversations can find them from any directory
+pub fn check_images(paths: &[String]) -> Result<Vec<String>> {
+    paths
+        .iter()
+        .map(|p| {
+            read_image(Path::new(p))
+                .and_then(|_| Ok(fs::canonicalize(p)?.to_string_lossy().to_string()))
+                .map_err(|e| anyhow!("Cannot attach image \"{}\" -> {}", p, e))
+        })
+        .collect()
+}
+
+/// Base64 encoded contents of each PNG or JPEG image, as sent to Ollama
+pub fn encode_images(paths: &[String]) -> Result<Vec<String>> {
+    paths
+        .iter()
+        .map(|p| {
+            read_image(Path::new(p))
+                .map(|bytes| STANDARD.encode(bytes))
+                .map_err(|e| anyhow!("Cannot attach image \"{}\" -> {}", p, e))
+        })
+        .collect()
+}
+
+fn read_image(path: &Path) -> Result<Vec<u8>> {
+    let size = fs::metadata(path)?.len();
+    if size > MAX_IMAGE_SIZE {
+        bail!(
+            "{} MiB is over the {} MiB limit",
+            size / (1024 * 1024),
+            MAX_IMAGE_SIZE / (1024 * 1024)
+        );
+    }
+    let bytes = fs::read(path)?;
+    // Checked by signature rather than extension
+    let png = bytes.starts_with(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+    let jpeg = bytes.starts_with(&[0xff, 0xd8, 0xff]);
+    if !png && !jpeg {
+        bail!("not a PNG or JPEG image");
+    }
+    Ok(bytes)
+}
+
+/// Files matched by a path, directory (read recursively) or glob pattern
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let matched: Vec<PathBuf> = match is_glob(pattern) {
+        true => glob::glob(pattern)
+            .map_err(|e| anyhow!("Invalid pattern \"{}\" -> {}", pattern, e))?
+            .filter_map(|p| p.ok())
+            .collect(),
+        false => vec![PathBuf::from(pattern)],
+    };
+    if matched.is_empty() {
+        bail!("No files match \"{}\"", pattern);
+    }
+    let mut files: Vec<PathBuf> = vec![];
+    for path in matched {
+        match path.is_dir() {
+            true => walk(&path, &mut files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(files)
+}
+
+// Files below dir in name order, skipping hidden entries (.git etc.) and
+// symlinked directories, which could link back up and never end
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            !p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    entries.sort();
+    for path in entries {
+        let link = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+        match (path.is_dir(), link) {
+            (true, false) => walk(&path, files)?,
+            (true, true) => (),
+            (false, _) => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+/// Contents of a text file within `MAX_FILE_SIZE`
+pub fn read(path: &Path) -> Result<String> {
+    let size = fs::metadata(path)?.len();
+    if size > MAX_FILE_SIZE {
+        bail!(
+            "{} KiB is over the {} KiB limit",
+            size / 1024,
+            MAX_FILE_SIZE / 1024
+        );
+    }
+    let bytes = fs::read(path)?;
+    if is_binary(&bytes) {
+        bail!("binary file");
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow!("binary file"))
+}
+
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+// Fence language from the file extension (or name, for e.g. Makefile)
+fn language(path: &str) -> &'static str {
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match name.as_str() {
+        "makefile" => return "make",
+        "dockerfile" => return "dockerfile",
+        "cmakelists.txt" => return "cmake",
+        _ => (),
+    }
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "jsx" => "jsx",
+        "tsx" => "tsx",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "cs" => "csharp",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "swift" => "swift",
+        "rb" => "ruby",
+        "php" => "php",
+        "lua" => "lua",
+        "zig" => "zig",
+        "hs" => "haskell",
+        "ex" | "exs" => "elixir",
+        "scala" => "scala",
+        "r" => "r",
+        "sh" | "bash" | "zsh" => "bash",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "xml" => "xml",
+        "md" | "markdown" => "markdown",
+        "ini" | "cfg" => "ini",
+        "diff" | "patch" => "diff",
+        _ => "text",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, bytes: &[u8]) -> String {
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn binary_is_a_nul_in_the_first_bytes() {
+        assert!(!is_binary(b"fn main() {}\n"));
+        assert!(!is_binary(b""));
+        assert!(is_binary(b"\x7fELF\0\0"));
+        let mut late = vec![b'a'; BINARY_SNIFF_LEN];
+        late.push(0);
+        assert!(!is_binary(&late));
+        late[BINARY_SNIFF_LEN - 1] = 0;
+        assert!(is_binary(&late));
+    }
+
+    #[test]
+    fn read_refuses_large_and_binary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let max = write(dir.path(), "max.txt", &vec![b'a'; MAX_FILE_SIZE as usize]);
+        assert!(read(Path::new(&max)).is_ok());
+        let over = write(
+            dir.path(),
+            "over.txt",
+            &vec![b'a'; MAX_FILE_SIZE as usize + 1],
+        );
+        let err = read(Path::new(&over)).unwrap_err().to_string();
+        assert_eq!(err, "256 KiB is over the 256 KiB limit");
+        let bin = write(dir.path(), "a.bin", b"ab\0cd");
+        assert_eq!(
+            read(Path::new(&bin)).unwrap_err().to_string(),
+            "binary file"
+        );
+        let latin1 = write(dir.path(), "latin1.txt", b"caf\xe9");
+        assert!(read(Path::new(&latin1)).is_err());
+    }
+
+    #[test]
+    fn collect_skips_found_files_and_limits_the_total() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = write(dir.path(), "a.txt", b"hello\n");
+        let bin = write(dir.path(), "b.bin", b"\0");
+        let (attachments, skipped) = collect(&[dir.path().to_string_lossy().to_string()]).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].path, text);
+        assert_eq!(skipped, [format!("{} (binary file)", bin)]);
+        // Named files must be attachable
+        assert!(collect(&[bin]).is_err());
+
+        let chunk = vec![b'a'; MAX_FILE_SIZE as usize];
+        let paths: Vec<String> = (0..4)
+            .map(|i| write(dir.path(), &format!("{}.txt", i), &chunk))
+            .collect();
+        assert!(collect(&paths).is_ok());
+        let paths = [paths, vec![text]].concat();
+        let err = collect(&paths).err().unwrap().to_string();
+        assert_eq!(err, "Attachments total 1024 KiB, limit is 1024 KiB");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_skips_symlinked_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let file = write(&dir.path().join("src"), "main.rs", b"fn main() {}\n");
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("src/loop")).unwrap();
+        let files = expand(&dir.path().to_string_lossy()).unwrap();
+        assert_eq!(files, [PathBuf::from(file)]);
+    }
+
+    #[test]
+    fn fence_is_longer_than_backticks_inside() {
+        assert_eq!(
+            fenced("a.rs", "a.rs", "let x = 1;\n"),
+            "```rust a.rs\nlet x = 1;\n```"
+        );
+        let md = "Run:\n```sh\nmake\n```\n";
+        assert_eq!(
+            fenced("README.md", "README.md", md),
+            "````markdown README.md\nRun:\n```sh\nmake\n```\n````"
+        );
+        assert!(fenced("a", "a", "`````").starts_with("``````text a\n"));
+    }
+
+    #[test]
+    fn language_from_extension_or_name() {
+        assert_eq!(language("src/main.rs"), "rust");
+        assert_eq!(language("App.TSX"), "tsx");
+        assert_eq!(language("build/Makefile"), "make");
+        assert_eq!(language("CMakeLists.txt"), "cmake");
+        assert_eq!(language("notes.txt"), "text");
+        assert_eq!(language("LICENSE"), "text");
+    }
+}
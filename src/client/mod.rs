@@ -0,0 +1,784 @@
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use reqwest::blocking::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Config;
+
+#[derive(Default, Serialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    // Base64 encoded images for multimodal models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    // "json" or a JSON schema the answer must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<ModelOptions>,
+    // How long the model stays loaded: seconds, or a duration such as "10m"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+}
+
+#[derive(Default, Serialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub stream: bool,
+    // "json" or a JSON schema the answer must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<ModelOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+}
+
+/// Model parameters sent as the request `options` object. Unset fields are
+/// omitted so the model's own defaults apply.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+// Body for /api/pull and /api/push
+#[derive(Serialize)]
+pub struct PullRequest {
+    pub name: String,
+    pub stream: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeleteRequest {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct ShowRequest {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct CopyRequest {
+    pub source: String,
+    pub destination: String,
+}
+
+#[derive(Serialize)]
+pub struct CreateRequest {
+    pub name: String,
+    pub modelfile: String,
+    pub stream: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GenerateResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub model: Option<String>,
+    pub created_at: Option<String>,
+    pub response: Option<String>,
+    pub done: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<i64>>,
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub eval_duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatResponse {
+    pub error: Option<String>,
+    pub model: Option<String>,
+    pub created_at: Option<String>,
+    pub message: Option<ChatMessage>,
+    pub done: Option<bool>,
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub eval_duration: Option<u64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    // Base64 encoded images for multimodal models
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ModelResponse {
+    pub models: Vec<Model>,
+}
+
+// Entry in /api/tags, or in /api/ps for models loaded in memory
+#[derive(Serialize, Deserialize)]
+pub struct Model {
+    pub name: String,
+    #[serde(default)]
+    pub modified_at: String,
+    pub size: i64,
+    pub digest: String,
+    pub details: Details,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>, // When a loaded model is unloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_vram: Option<i64>, // Bytes of a loaded model held in GPU memory
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Details {
+    pub format: String,
+    pub family: String,
+    pub families: Option<Vec<String>>,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+// Progress of a pull, push or create
+#[derive(Serialize, Deserialize)]
+pub struct PullResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShowResponse {
+    #[serde(default)]
+    pub modelfile: String,
+    #[serde(default)]
+    pub parameters: String,
+    #[serde(default)]
+    pub template: String,
+    #[serde(default)]
+    pub license: String,
+    #[serde(default)]
+    pub system: String,
+    pub details: Option<Details>,
+}
+
+/// Texts to embed in one request; Ollama returns one vector per input.
+#[derive(Default, Serialize)]
+pub struct EmbedRequest {
+    pub model: String,
+    pub input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<ModelOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct EmbedResponse {
+    pub model: Option<String>,
+    #[serde(default)]
+    pub embeddings: Vec<Vec<f32>>,
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+}
+
+// Chat answers are reported like generate answers
+impl From<ChatResponse> for GenerateResponse {
+    fn from(resp: ChatResponse) -> Self {
+        GenerateResponse {
+            error: resp.error,
+            model: resp.model,
+            created_at: resp.created_at,
+            response: resp.message.map(|m| m.content),
+            done: resp.done,
+            context: None,
+            total_duration: resp.total_duration,
+            load_duration: resp.load_duration,
+            prompt_eval_count: resp.prompt_eval_count,
+            prompt_eval_duration: resp.prompt_eval_duration,
+            eval_count: resp.eval_count,
+            eval_duration: resp.eval_duration,
+        }
+    }
+}
+
+impl ModelOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == ModelOptions::default()
+    }
+
+    /// Options with every field set in `other` taking precedence
+    pub fn merge(&self, other: &ModelOptions) -> ModelOptions {
+        ModelOptions {
+            temperature: other.temperature.or(self.temperature),
+            num_ctx: other.num_ctx.or(self.num_ctx),
+            seed: other.seed.or(self.seed),
+            top_p: other.top_p.or(self.top_p),
+            stop: other.stop.clone().or(self.stop.clone()),
+        }
+    }
+}
+
+impl fmt::Display for ModelOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut opts: Vec<String> = vec![];
+        if let Some(t) = self.temperature {
+            opts.push(format!("temperature={}", t));
+        }
+        if let Some(n) = self.num_ctx {
+            opts.push(format!("num_ctx={}", n));
+        }
+        if let Some(s) = self.seed {
+            opts.push(format!("seed={}", s));
+        }
+        if let Some(p) = self.top_p {
+            opts.push(format!("top_p={}", p));
+        }
+        if let Some(stop) = &self.stop {
+            opts.push(format!("stop={:?}", stop));
+        }
+        write!(f, "{}", opts.join(", "))
+    }
+}
+
+/// Blocking client for an Ollama server. Holds a single connection pool and
+/// returns typed responses without printing anything.
+pub struct OllamaClient {
+    base_url: String,
+    http: Client,
+}
+
+impl OllamaClient {
+    /// Client for `http://host:port`. A `timeout` of `None` waits indefinitely,
+    /// which generation on large models usually needs.
+    pub fn new(host: &str, port: u16, timeout: Option<Duration>) -> Result<Self> {
+        let http = Client::builder().timeout(timeout).build()?;
+        Ok(OllamaClient {
+            base_url: format!("http://{}:{}", host, port),
+            http,
+        })
+    }
+
+    pub fn from_config(conf: &Config) -> Result<Self> {
+        Self::new(&conf.host, conf.port, None)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Succeeds if the server answers on its root path.
+    pub fn ping(&self) -> Result<()> {
+        self.http.get(&self.base_url).send()?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Models available on the server (`/api/tags`).
+    pub fn tags(&self) -> Result<Vec<Model>> {
+        let resp = check_status(self.http.get(self.url("tags")).send()?)?;
+        let models: ModelResponse = resp.json()?;
+        Ok(models.models)
+    }
+
+    /// Generate a full response in one call.
+    pub fn generate(&self, mut req: GenerateRequest) -> Result<GenerateResponse> {
+        req.stream = false;
+        self.post_json(&self.url("generate"), &req)
+    }
+
+    /// Generate a response, calling `on_chunk` for every streamed chunk. The
+    /// returned final chunk holds the metrics and the full response text.
+    pub fn generate_stream<F>(
+        &self,
+        mut req: GenerateRequest,
+        on_chunk: F,
+    ) -> Result<GenerateResponse>
+    where
+        F: FnMut(&GenerateResponse) -> Result<()>,
+    {
+        req.stream = true;
+        self.post_stream(&self.url("generate"), &req, on_chunk)
+    }
+
+    /// Chat completion for a message history in one call.
+    pub fn chat(&self, mut req: ChatRequest) -> Result<ChatResponse> {
+        req.stream = false;
+        self.post_json(&self.url("chat"), &req)
+    }
+
+    /// Chat completion, calling `on_chunk` for every streamed chunk. The
+    /// returned final chunk holds the metrics and the full message.
+    pub fn chat_stream<F>(&self, mut req: ChatRequest, on_chunk: F) -> Result<ChatResponse>
+    where
+        F: FnMut(&ChatResponse) -> Result<()>,
+    {
+        req.stream = true;
+        self.post_stream(&self.url("chat"), &req, on_chunk)
+    }
+
+    /// Pull a model to the server, blocking until the download completes.
+    pub fn pull(&self, name: &str) -> Result<PullResponse> {
+        let req = PullRequest {
+            name: name.to_string(),
+            stream: false,
+        };
+        let resp: PullResponse = self.post_json(&self.url("pull"), &req)?;
+        if let Some(err) = resp.error {
+            bail!(err);
+        }
+        Ok(resp)
+    }
+
+    /// Pull a model, calling `on_status` with each progress update. Dropping
+    /// the stream early (an `Err` from `on_status`) leaves the downloaded
+    /// layers on the server, where the next pull resumes from them.
+    pub fn pull_stream<F>(&self, name: &str, on_status: F) -> Result<PullResponse>
+    where
+        F: FnMut(&PullResponse) -> Result<()>,
+    {
+        let req = PullRequest {
+            name: name.to_string(),
+            stream: true,
+        };
+        self.post_stream(&self.url("pull"), &req, on_status)
+    }
+
+    /// Copy a model under a new name.
+    pub fn copy(&self, source: &str, destination: &str) -> Result<()> {
+        let req = CopyRequest {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        };
+        check_status(self.http.post(self.url("copy")).json(&req).send()?)?;
+        Ok(())
+    }
+
+    /// Modelfile, parameters, template and license of a model.
+    pub fn show(&self, name: &str) -> Result<ShowResponse> {
+        let req = ShowRequest {
+            name: name.to_string(),
+        };
+        self.post_json(&self.url("show"), &req)
+    }
+
+    /// Embedding vectors for each input, in input order.
+    pub fn embed(&self, req: EmbedRequest) -> Result<EmbedResponse> {
+        let count = req.input.len();
+        let resp: EmbedResponse = self.post_json(&self.url("embed"), &req)?;
+        if resp.embeddings.len() != count {
+            bail!(
+                "Expected {} embeddings, server returned {}",
+                count,
+                resp.embeddings.len()
+            );
+        }
+        Ok(resp)
+    }
+
+    /// Models loaded in memory (`/api/ps`).
+    pub fn ps(&self) -> Result<Vec<Model>> {
+        let resp = check_status(self.http.get(self.url("ps")).send()?)?;
+        let models: ModelResponse = resp.json()?;
+        Ok(models.models)
+    }
+
+    /// Create a model from the contents of a Modelfile, calling `on_status`
+    /// for every progress update.
+    pub fn create_stream<F>(
+        &self,
+        name: &str,
+        modelfile: &str,
+        on_status: F,
+    ) -> Result<PullResponse>
+    where
+        F: FnMut(&PullResponse) -> Result<()>,
+    {
+        let req = CreateRequest {
+            name: name.to_string(),
+            modelfile: modelfile.to_string(),
+            stream: true,
+        };
+        self.post_stream(&self.url("create"), &req, on_status)
+    }
+
+    /// Push a model to its registry, calling `on_status` for every progress update.
+    pub fn push_stream<F>(&self, name: &str, on_status: F) -> Result<PullResponse>
+    where
+        F: FnMut(&PullResponse) -> Result<()>,
+    {
+        let req = PullRequest {
+            name: name.to_string(),
+            stream: true,
+        };
+        self.post_stream(&self.url("push"), &req, on_status)
+    }
+
+    /// Delete a model from the server.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let req = DeleteRequest {
+            name: name.to_string(),
+        };
+        check_status(self.http.delete(self.url("delete")).json(&req).send()?)?;
+        Ok(())
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}/api/{}", self.base_url, endpoint)
+    }
+
+    fn post_json<B: serde::Serialize, T: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<T> {
+        let resp = check_status(self.http.post(url).json(body).send()?)?;
+        Ok(resp.json()?)
+    }
+
+    fn post_stream<B, T, F>(&self, url: &str, body: &B, mut on_chunk: F) -> Result<T>
+    where
+        B: serde::Serialize,
+        T: StreamChunk,
+        F: FnMut(&T) -> Result<()>,
+    {
+        let resp = check_status(self.http.post(url).json(body).send()?)?;
+        let mut text = String::new();
+        for line in BufReader::new(resp).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut chunk: T = serde_json::from_str(&line)?;
+            if let Some(err) = chunk.take_error() {
+                bail!(err);
+            }
+            if let Some(token) = chunk.token() {
+                text.push_str(token);
+            }
+            on_chunk(&chunk)?;
+            if chunk.is_done() {
+                chunk.set_text(text);
+                return Ok(chunk);
+            }
+        }
+        bail!("Response stream ended unexpectedly")
+    }
+}
+
+// Chunk of an NDJSON response stream
+trait StreamChunk: DeserializeOwned {
+    fn take_error(&mut self) -> Option<String>;
+    fn token(&self) -> Option<&str>;
+    fn is_done(&self) -> bool;
+    fn set_text(&mut self, text: String);
+}
+
+impl StreamChunk for GenerateResponse {
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    fn token(&self) -> Option<&str> {
+        self.response.as_deref()
+    }
+
+    fn is_done(&self) -> bool {
+        self.done.unwrap_or(false)
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.response = Some(text);
+    }
+}
+
+impl StreamChunk for ChatResponse {
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    fn token(&self) -> Option<&str> {
+        self.message.as_ref().map(|m| m.content.as_str())
+    }
+
+    fn is_done(&self) -> bool {
+        self.done.unwrap_or(false)
+    }
+
+    fn set_text(&mut self, text: String) {
+        match self.message.as_mut() {
+            Some(m) => m.content = text,
+            None => {
+                self.message = Some(ChatMessage {
+                    role: "assistant".to_string(),
+                    content: text,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+impl StreamChunk for PullResponse {
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    fn token(&self) -> Option<&str> {
+        None
+    }
+
+    fn is_done(&self) -> bool {
+        self.status.as_deref() == Some("success")
+    }
+
+    fn set_text(&mut self, _text: String) {}
+}
+
+// Turns non-success responses into errors, preferring Ollama's `error` message
+fn check_status(resp: Response) -> Result<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let text = resp.text().unwrap_or_default();
+    match serde_json::from_str::<ErrorResponse>(&text) {
+        Ok(e) => bail!(e.error),
+        Err(_) => bail!("Server responded with {}", status),
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NASTY: &[&str] = &[
+        "plain",
+        "say \"hi\"",
+        "C:\\Users\\r2\\file.txt",
+        "trailing backslash \\",
+        "line one\nline two\r\n\ttabbed",
+        "nul \u{0} bell \u{7} escape \u{1b}[31mred\u{1b}[0m",
+        "unicode ✔ ▹ 日本語 🦀 \u{2028}\u{2029}",
+        "{\"model\":\"evil\",\"stream\":true}",
+        "\",\"stream\":false,\"x\":\"",
+        "</script><!-- ' -->",
+        "",
+    ];
+
+    fn to_value<T: serde::Serialize>(req: &T) -> Value {
+        let body = serde_json::to_string(req).unwrap();
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    fn generate_request_round_trips_prompt() {
+        for prompt in NASTY {
+            let req = GenerateRequest {
+                model: "llama3:latest".to_string(),
+                prompt: prompt.to_string(),
+                stream: true,
+                system: Some(prompt.to_string()),
+                ..Default::default()
+            };
+            let v = to_value(&req);
+            assert_eq!(v["prompt"].as_str().unwrap(), *prompt);
+            assert_eq!(v["system"].as_str().unwrap(), *prompt);
+            assert_eq!(v["model"], "llama3:latest");
+            assert_eq!(v["stream"], true);
+            assert!(v.get("context").is_none());
+        }
+    }
+
+    #[test]
+    fn generate_request_sends_context_as_array() {
+        let req = GenerateRequest {
+            model: "llama3:latest".to_string(),
+            prompt: "hi".to_string(),
+            stream: false,
+            context: Some(vec![1, 2, 128006]),
+            ..Default::default()
+        };
+        let v = to_value(&req);
+        assert_eq!(v["context"], serde_json::json!([1, 2, 128006]));
+    }
+
+    #[test]
+    fn chat_request_round_trips_messages() {
+        let messages: Vec<ChatMessage> = NASTY
+            .iter()
+            .map(|c| ChatMessage {
+                role: "user".to_string(),
+                content: c.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let req = ChatRequest {
+            model: "llama3:latest".to_string(),
+            messages,
+            stream: true,
+            ..Default::default()
+        };
+        let v = to_value(&req);
+        let sent = v["messages"].as_array().unwrap();
+        assert_eq!(sent.len(), NASTY.len());
+        for (msg, content) in sent.iter().zip(NASTY) {
+            assert_eq!(msg["role"], "user");
+            assert_eq!(msg["content"].as_str().unwrap(), *content);
+        }
+    }
+
+    #[test]
+    fn images_only_sent_when_attached() {
+        let msg = ChatMessage {
+            role: "user".to_string(),
+            content: "what is this?".to_string(),
+            ..Default::default()
+        };
+        assert!(to_value(&msg).get("images").is_none());
+        let msg = ChatMessage {
+            images: vec!["iVBORw0KGgo=".to_string()],
+            ..msg
+        };
+        assert_eq!(
+            to_value(&msg)["images"],
+            serde_json::json!(["iVBORw0KGgo="])
+        );
+        let req = GenerateRequest {
+            images: Some(vec!["/9j/4AAQ".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(to_value(&req)["images"], serde_json::json!(["/9j/4AAQ"]));
+    }
+
+    #[test]
+    fn format_sends_json_or_schema() {
+        let req = GenerateRequest {
+            format: Some(Value::String("json".to_string())),
+            ..Default::default()
+        };
+        assert_eq!(to_value(&req)["format"], "json");
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"name": {"type": "string", "pattern": "^\\w+\"$"}},
+            "required": ["name"]
+        });
+        let req = ChatRequest {
+            format: Some(schema.clone()),
+            ..Default::default()
+        };
+        assert_eq!(to_value(&req)["format"], schema);
+        assert!(to_value(&ChatRequest::default()).get("format").is_none());
+    }
+
+    #[test]
+    fn embed_sends_batch_and_parses_vectors() {
+        let req = EmbedRequest {
+            model: "nomic-embed-text:latest".to_string(),
+            input: NASTY.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let body = to_value(&req);
+        assert_eq!(body["input"].as_array().unwrap().len(), NASTY.len());
+        assert_eq!(body["input"][2], NASTY[2]);
+        assert!(body.get("options").is_none() && body.get("keep_alive").is_none());
+
+        let resp: EmbedResponse = serde_json::from_str(
+            r#"{"model":"nomic-embed-text","embeddings":[[0.1,-0.2],[3e-5,1]],"total_duration":42}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.embeddings, vec![vec![0.1, -0.2], vec![3e-5, 1.0]]);
+        assert!(resp.prompt_eval_count.is_none());
+    }
+
+    #[test]
+    fn options_only_send_set_fields() {
+        let req = ChatRequest {
+            model: "llama3:latest".to_string(),
+            options: Some(ModelOptions {
+                temperature: Some(0.0),
+                stop: Some(vec!["\n\n".to_string()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let v = to_value(&req);
+        assert_eq!(
+            v["options"],
+            serde_json::json!({"temperature": 0.0, "stop": ["\n\n"]})
+        );
+        let v = to_value(&ChatRequest::default());
+        assert!(v.get("options").is_none());
+    }
+
+    #[test]
+    fn pull_and_delete_requests_round_trip_name() {
+        for name in NASTY {
+            let v = to_value(&PullRequest {
+                name: name.to_string(),
+                stream: false,
+            });
+            assert_eq!(v["name"].as_str().unwrap(), *name);
+            assert_eq!(v["stream"], false);
+            let v = to_value(&DeleteRequest {
+                name: name.to_string(),
+            });
+            assert_eq!(v["name"].as_str().unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn tags_and_ps_entries_parse_as_model() {
+        let details = r#"{"format":"gguf","family":"llama","families":null,"parameter_size":"8.0B","quantization_level":"Q4_0"}"#;
+        let tag = format!(
+            r#"{{"name":"llama3:latest","modified_at":"2024-05-01T10:00:00Z","size":4661224676,"digest":"365c0bd3c000","details":{}}}"#,
+            details
+        );
+        let model: Model = serde_json::from_str(&tag).unwrap();
+        assert_eq!(model.size, 4661224676);
+        assert!(model.expires_at.is_none() && model.size_vram.is_none());
+        let running = format!(
+            r#"{{"name":"llama3:latest","model":"llama3:latest","size":5137025024,"digest":"365c0bd3c000","details":{},"expires_at":"2024-06-04T14:38:31.83753-07:00","size_vram":5137025024}}"#,
+            details
+        );
+        let model: Model = serde_json::from_str(&running).unwrap();
+        assert_eq!(model.modified_at, "");
+        assert_eq!(model.size_vram, Some(5137025024));
+        assert!(to_value(&model).get("expires_at").is_some());
+    }
+}
@@ -0,0 +1,26 @@
+-- rtwo.db as saved by releases up to 0.1.4: one Conversations row per saved
+-- conversation with its messages as JSON, and the context as a quoted string
+CREATE TABLE IF NOT EXISTS Conversations (timestamp INTEGER, host TEXT, model TEXT, conversation TEXT, context TEXT);
+
+INSERT INTO Conversations VALUES (
+    1714561200000,
+    'localhost:11434',
+    'llama3:latest',
+    '[{"role":"user","content":"Why does the build fail?\n\n```rust\nfn main() {}\n```"},{"role":"assistant","content":"The migrations table is missing."},{"role":"user","content":"How do I add it?"},{"role":"assistant","content":"Create it when the database is opened."}]',
+    '"[1, 2, 3]"'
+);
+INSERT INTO Conversations VALUES (
+    1714647600000,
+    '192.168.1.5:11434',
+    'llava:latest',
+    '[{"role":"user","content":"What is in this picture?"},{"role":"assistant","content":"A cat asleep on a keyboard."}]',
+    '"[4, 5]"'
+);
+-- Saved without a context
+INSERT INTO Conversations VALUES (
+    1714734000000,
+    'localhost:11434',
+    'mistral:latest',
+    '[{"role":"user","content":"hi"},{"role":"assistant","content":"Hello!"}]',
+    '[]'
+);
@@ -1,77 +1,523 @@
-use anyhow::{anyhow, bail, Result};
-use chrono::{DateTime, Local};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use chrono::{DateTime, Days, Local, NaiveDate, TimeZone};
 use dialoguer::{theme::ColorfulTheme, Confirm, MultiSelect, Select};
+use lib::client::OllamaClient;
 use rusqlite::Connection;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{kb, ollama};
+
 #[derive(Clone)]
 struct DBEntry {
+    id: i64,
     timestamp: u64,
     host: String,
     model: String,
     conversation: Vec<Chat>,
     context: String,
+    system: Option<String>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+pub struct Restored {
+    pub id: i64,
+    pub conversation: Vec<Chat>,
+    pub context: Option<Vec<i64>>,
+    pub system: Option<String>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Chat {
     pub role: String,
     pub content: String,
+    // Paths of files attached to a prompt (their contents are in `content`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+    // Paths of images sent with a prompt, re-read when the turn is sent again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    // When the message was sent or answered, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    // Model that wrote an answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // Prompt and answer tokens, and the time spent writing the answer (ns)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_eval_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_duration: Option<u64>,
+    // Knowledge base chunks sent ahead of a prompt; not saved
+    #[serde(skip)]
+    pub sources: Vec<kb::Source>,
+    // Prompt as typed, which `content` ends with after any attachments; not saved
+    #[serde(skip)]
+    pub prompt: Option<String>,
 }
 
-const DB_CREATE_STMT: &str = "CREATE TABLE IF NOT EXISTS Conversations (timestamp INTEGER, host TEXT, model TEXT, conversation TEXT, context TEXT)";
-const DB_INSERT_STMT: &str = "INSERT INTO Conversations (timestamp, host, model, conversation, context) VALUES (?1, ?2, ?3, ?4, ?5)";
+impl Chat {
+    /// Content as sent to the model, with any retrieved sources ahead of it
+    pub fn request_content(&self) -> String {
+        kb::compose(&self.content, &self.sources)
+    }
+}
+
+// Schema version 1. Messages are indexed for full-text search by triggers, and
+// deleting a conversation deletes its messages and their embeddings. Files
+// indexed by "rtwo index" and their chunks are kept by kb directory
+const DB_SCHEMA_V1: &str = "
+CREATE TABLE Conversations (id INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL, host TEXT, model TEXT, context TEXT, system TEXT);
+CREATE TABLE Messages (id INTEGER PRIMARY KEY, conversation_id INTEGER NOT NULL REFERENCES Conversations (id) ON DELETE CASCADE, position INTEGER NOT NULL, timestamp INTEGER, role TEXT NOT NULL, content TEXT NOT NULL, model TEXT, attachments TEXT, images TEXT, prompt_eval_count INTEGER, eval_count INTEGER, eval_duration INTEGER);
+CREATE INDEX MessagesByConversation ON Messages (conversation_id, position);
+CREATE TABLE MessageEmbeddings (message_id INTEGER NOT NULL REFERENCES Messages (id) ON DELETE CASCADE, model TEXT NOT NULL, embedding BLOB, PRIMARY KEY (message_id, model));
+CREATE VIRTUAL TABLE MessagesFts USING fts5(content, content = 'Messages', content_rowid = 'id', tokenize = 'porter unicode61');
+CREATE TRIGGER MessagesFtsInsert AFTER INSERT ON Messages BEGIN
+    INSERT INTO MessagesFts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TRIGGER MessagesFtsDelete AFTER DELETE ON Messages BEGIN
+    INSERT INTO MessagesFts (MessagesFts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
+CREATE TRIGGER MessagesFtsUpdate AFTER UPDATE OF content ON Messages BEGIN
+    INSERT INTO MessagesFts (MessagesFts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO MessagesFts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TABLE IndexedFiles (kb TEXT, path TEXT, mtime INTEGER, hash TEXT, model TEXT, PRIMARY KEY (kb, path));
+CREATE TABLE Chunks (kb TEXT, path TEXT, start_line INTEGER, end_line INTEGER, content TEXT, embedding BLOB);
+";
+const DB_CREATE_VERSION_STMT: &str =
+    "CREATE TABLE IF NOT EXISTS SchemaVersion (version INTEGER PRIMARY KEY, applied INTEGER)";
+const DB_SELECT_VERSION_STMT: &str = "SELECT COALESCE(MAX(version), 0) FROM SchemaVersion";
+const DB_INSERT_VERSION_STMT: &str = "INSERT INTO SchemaVersion (version, applied) VALUES (?1, ?2)";
+const DB_HAS_TABLE_STMT: &str =
+    "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=(?1)";
+
+// The single table of releases up to 0.1.4, with each conversation as JSON
+const LEGACY_RENAME_STMT: &str = "ALTER TABLE Conversations RENAME TO LegacyConversations";
+const LEGACY_SELECT_STMT: &str = "SELECT timestamp, host, model, conversation, context FROM LegacyConversations ORDER BY timestamp";
+const LEGACY_DROP_STMT: &str = "DROP TABLE LegacyConversations";
+
+const DB_INSERT_STMT: &str = "INSERT INTO Conversations (timestamp, host, model, context, system) VALUES (?1, ?2, ?3, ?4, ?5)";
+const DB_INSERT_MESSAGE_STMT: &str = "INSERT INTO Messages (conversation_id, position, timestamp, role, content, model, attachments, images, prompt_eval_count, eval_count, eval_duration) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
 const DB_SELECT_STMT: &str =
-    "SELECT timestamp, host, model, conversation, context FROM Conversations";
-const DB_DELETE_STMT: &str = "DELETE FROM Conversations WHERE timestamp=(?1)";
+    "SELECT id, timestamp, host, model, context, system FROM Conversations ORDER BY id";
+const DB_SELECT_MESSAGES_STMT: &str = "SELECT conversation_id, role, content, attachments, images, timestamp, model, prompt_eval_count, eval_count, eval_duration FROM Messages ORDER BY conversation_id, position";
+const DB_DELETE_STMT: &str = "DELETE FROM Conversations WHERE id=(?1)";
+const DB_UPDATE_STMT: &str = "UPDATE Conversations SET timestamp=(?1), host=(?2), model=(?3), context=(?4), system=(?5) WHERE id=(?6)";
+const DB_DELETE_MESSAGES_STMT: &str = "DELETE FROM Messages WHERE conversation_id=(?1)";
+const DB_SELECT_UNEMBEDDED_STMT: &str = "SELECT id, content FROM Messages WHERE trim(content) != '' AND id NOT IN (SELECT message_id FROM MessageEmbeddings WHERE model=(?1))";
+const DB_SELECT_EMBEDDINGS_STMT: &str = "SELECT m.conversation_id, m.position, e.embedding FROM MessageEmbeddings e JOIN Messages m ON m.id = e.message_id WHERE e.model=(?1)";
+const DB_INSERT_EMBEDDING_STMT: &str =
+    "INSERT OR REPLACE INTO MessageEmbeddings (message_id, model, embedding) VALUES (?1, ?2, ?3)";
+// Matching messages, best first, with the matched terms between MATCH_START and
+// MATCH_END in the snippet
+const DB_GREP_STMT: &str = "SELECT c.id, c.timestamp, m.position, m.role, snippet(MessagesFts, 0, char(2), char(3), '…', 16), c.model, c.host \
+     FROM MessagesFts JOIN Messages m ON m.id = MessagesFts.rowid JOIN Conversations c ON c.id = m.conversation_id \
+     WHERE MessagesFts MATCH ?1 \
+     AND (?2 IS NULL OR c.model = ?2 OR c.model = ?2 || ':latest') \
+     AND (?3 IS NULL OR c.host = ?3 OR substr(c.host, 1, length(?3) + 1) = ?3 || ':') \
+     AND (?4 IS NULL OR c.timestamp >= ?4) AND (?5 IS NULL OR c.timestamp < ?5) \
+     ORDER BY rank LIMIT ?6";
 
+// Control characters around matched terms, which typed or generated text never has
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+// Migrations in order; the schema version is the number applied
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[normalize_conversations];
+
+// Characters of a message embedded for search; the start of a long answer is
+// enough to find it by
+const SEARCH_MAX_CHARS: usize = 2000;
+
+/// Saves the conversation, replacing the saved copy `id` if it is still there.
+/// Returns the id it is saved under
 pub fn save_conversation(
-    conversation: Vec<Chat>,
-    context: Option<String>,
+    id: Option<i64>,
+    conversation: &[Chat],
+    context: Option<Vec<i64>>,
+    system: Option<&str>,
     conf: &lib::Config,
-) -> Result<()> {
+) -> Result<Option<i64>> {
     if conversation.is_empty() {
-        return Ok(());
+        return Ok(id);
     }
-    let con = Connection::open(lib::get_project_file(lib::ProjFiles::Data)?)?;
-    con.execute(DB_CREATE_STMT, ())?;
-    let convo = serde_json::to_string(&conversation)?;
-    let ctx = match context {
-        Some(c) => format!("{:?}", c),
-        None => "[]".to_string(),
-    };
-    let now = Local::now().timestamp_millis();
+    let mut con = connect()?;
+    let ctx = serde_json::to_string(&context.unwrap_or_default())?;
     let host = format!("{}:{}", conf.host, conf.port);
-    con.execute(DB_INSERT_STMT, (now, host, conf.model.clone(), convo, ctx))?;
+    let tx = con.transaction()?;
+    let id = write_conversation(&tx, id, conversation, &host, &conf.model, &ctx, system)?;
+    tx.commit()?;
     lib::log(lib::LogLevel::Debug, "db", "Conversation saved to DB")?;
+    Ok(Some(id))
+}
+
+// Updates conversation `id` and replaces its messages, or inserts a new one
+fn write_conversation(
+    con: &Connection,
+    id: Option<i64>,
+    conversation: &[Chat],
+    host: &str,
+    model: &str,
+    context: &str,
+    system: Option<&str>,
+) -> Result<i64> {
+    let timestamp = Local::now().timestamp_millis();
+    let updated = match id {
+        Some(id) => con.execute(
+            DB_UPDATE_STMT,
+            (timestamp, host, model, context, system, id),
+        )?,
+        None => 0,
+    };
+    let id = match (id, updated) {
+        (Some(id), 1) => {
+            con.execute(DB_DELETE_MESSAGES_STMT, [id])?;
+            id
+        }
+        _ => insert_conversation(con, timestamp, host, model, context, system)?,
+    };
+    for (i, chat) in conversation.iter().enumerate() {
+        insert_message(con, id, i, chat)?;
+    }
+    Ok(id)
+}
+
+fn insert_conversation(
+    con: &Connection,
+    timestamp: i64,
+    host: &str,
+    model: &str,
+    context: &str,
+    system: Option<&str>,
+) -> Result<i64> {
+    con.execute(DB_INSERT_STMT, (timestamp, host, model, context, system))?;
+    Ok(con.last_insert_rowid())
+}
+
+fn insert_message(
+    con: &Connection,
+    conversation_id: i64,
+    position: usize,
+    chat: &Chat,
+) -> Result<()> {
+    // Path lists as JSON, NULL when empty
+    let paths = |paths: &[String]| match paths.is_empty() {
+        true => Ok(None),
+        false => serde_json::to_string(paths).map(Some),
+    };
+    con.execute(
+        DB_INSERT_MESSAGE_STMT,
+        (
+            conversation_id,
+            position,
+            chat.timestamp
+                .unwrap_or_else(|| Local::now().timestamp_millis()),
+            &chat.role,
+            &chat.content,
+            &chat.model,
+            paths(&chat.attachments)?,
+            paths(&chat.images)?,
+            chat.prompt_eval_count,
+            chat.eval_count,
+            chat.eval_duration,
+        ),
+    )?;
     Ok(())
 }
 
-pub fn restore_conversation(color: bool) -> Result<(Option<String>, Vec<Chat>)> {
-    let (entries, conversations) = get_conversation_entries()?;
+pub fn restore_conversation(color: bool) -> Result<Restored> {
+    let (entries, conversations) = get_conversation_entries(&connect()?)?;
+    let idx = choose_conversation(&conversations, color)?;
+    restore_entry(&entries[idx], color)
+}
+
+fn choose_conversation(conversations: &[String], color: bool) -> Result<usize> {
     let idx = match color {
         true => Select::with_theme(&ColorfulTheme::default())
             .with_prompt("Choose conversation to restore")
-            .items(&conversations[..])
+            .items(conversations)
             .report(false)
             .interact()?,
         false => Select::new()
             .with_prompt("Choose conversation to restore")
-            .items(&conversations[..])
+            .items(conversations)
             .report(false)
             .interact()?,
     };
+    Ok(idx)
+}
+
+// Prints a saved conversation and returns it to continue from
+fn restore_entry(entry: &DBEntry, color: bool) -> Result<Restored> {
     lib::fmt_print(
         &format!(
             "* Restoring conversation *\n{}",
-            get_time_from_ts(entries[idx].timestamp)?
+            get_time_from_ts(entry.timestamp)?
         ),
         lib::ContentType::Info,
         color,
     );
-    for chat in &entries[idx].conversation {
+    if let Some(system) = &entry.system {
+        lib::fmt_print(
+            &format!("System prompt: {}", system),
+            lib::ContentType::Info,
+            color,
+        );
+    }
+    print_conversation(&entry.conversation, color);
+    println!("\n");
+    let context: Option<Vec<i64>> = serde_json::from_str(&entry.context).ok();
+    Ok(Restored {
+        id: entry.id,
+        conversation: entry.conversation.clone(),
+        context,
+        system: entry.system.clone(),
+    })
+}
+
+/// Ranks saved conversations by how close their best matching message is to
+/// `query`, embedding any messages not yet embedded with `model`. The chosen
+/// conversation is restored; the JSON output modes print the matches instead
+/// and return None
+pub fn search_conversations(
+    query: &str,
+    model: &str,
+    limit: usize,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Option<Restored>> {
+    let mut con = connect()?;
+    let (entries, _) = get_conversation_entries(&con)?;
+    let missing: Vec<(i64, String)> = con
+        .prepare(DB_SELECT_UNEMBEDDED_STMT)?
+        .query_map([model], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    if !missing.is_empty() {
+        let texts: Vec<String> = missing
+            .iter()
+            .map(|(_, content)| content.chars().take(SEARCH_MAX_CHARS).collect())
+            .collect();
+        let embedded = ollama::embed_texts(&texts, model, client, conf)?;
+        let tx = con.transaction()?;
+        for ((id, _), vector) in missing.iter().zip(embedded) {
+            tx.execute(DB_INSERT_EMBEDDING_STMT, (id, model, kb::to_blob(&vector)))?;
+        }
+        tx.commit()?;
+        lib::log(
+            lib::LogLevel::Debug,
+            "db",
+            "Conversation embeddings saved to DB",
+        )?;
+    }
+    let query = ollama::embed_texts(&[query.to_string()], model, client, conf)?.remove(0);
+    let mut stmt = con.prepare(DB_SELECT_EMBEDDINGS_STMT)?;
+    let scores = stmt
+        .query_map([model], |row| {
+            let blob: Vec<u8> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, kb::from_blob(&blob)))
+        })?
+        .collect::<rusqlite::Result<Vec<(i64, usize, Vec<f32>)>>>()?;
+    let scores = scores
+        .iter()
+        .map(|(id, i, vector)| Ok((*id, *i, kb::cosine(&query, vector)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let by_id: HashMap<i64, &DBEntry> = entries.iter().map(|e| (e.id, e)).collect();
+    let hits: Vec<(&DBEntry, &Chat, f32)> = rank(scores.into_iter(), limit)
+        .into_iter()
+        .filter_map(|(id, i, score)| {
+            let entry = by_id.get(&id)?;
+            Some((*entry, entry.conversation.get(i)?, score))
+        })
+        .collect();
+    if lib::output().is_json() {
+        let mut items: Vec<serde_json::Value> = vec![];
+        for (entry, chat, score) in hits.iter() {
+            let mut item = list_item(entry)?;
+            item["score"] = serde_json::json!(score);
+            item["match"] = serde_json::json!(snippet(&chat.content));
+            items.push(item);
+        }
+        match lib::output() {
+            lib::Output::Ndjson => items.iter().for_each(lib::print_json),
+            _ => lib::print_json(&serde_json::json!({ "conversations": items })),
+        }
+        return Ok(None);
+    }
+    if hits.is_empty() {
+        bail!("No conversations embedded with \"{}\"", model);
+    }
+    let mut labels: Vec<String> = vec![];
+    for (entry, chat, score) in hits.iter() {
+        labels.push(format!(
+            "{}: {} ({:.2}) -> {:.64}",
+            get_time_from_ts(entry.timestamp)?,
+            entry.model,
+            score,
+            snippet(&chat.content)
+        ));
+    }
+    let idx = choose_conversation(&labels, conf.color)?;
+    restore_entry(hits[idx].0, conf.color).map(Some)
+}
+
+/// Filters for `grep_conversations`; dates are YYYY-MM-DD (local time) or RFC 3339
+#[derive(Default)]
+pub struct HistoryFilter<'a> {
+    pub model: Option<&'a str>,
+    pub host: Option<&'a str>,
+    pub since: Option<&'a str>,
+    pub until: Option<&'a str>,
+}
+
+/// Prints the saved messages containing all `terms`, best match first, with
+/// the terms highlighted in a snippet of each
+pub fn grep_conversations(
+    terms: &[String],
+    filter: &HistoryFilter,
+    limit: usize,
+    color: bool,
+) -> Result<()> {
+    let con = connect()?;
+    let since = filter.since.map(|d| parse_date(d, false)).transpose()?;
+    let until = filter.until.map(|d| parse_date(d, true)).transpose()?;
+    let mut stmt = con.prepare(DB_GREP_STMT)?;
+    let rows = stmt.query_map(
+        (
+            fts_query(terms),
+            filter.model,
+            filter.host,
+            since,
+            until,
+            limit as i64,
+        ),
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, usize>(2)?,
+                row.get::<_, String>(3)?,
+                highlight(&row.get::<_, String>(4)?),
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        },
+    )?;
+    let hits = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    if lib::output().is_json() {
+        let mut items: Vec<serde_json::Value> = vec![];
+        for (id, ts, message, role, snippet, model, host) in hits {
+            let time = DateTime::from_timestamp_millis(ts as i64)
+                .ok_or(anyhow!("Error parsing timestamp"))?;
+            items.push(serde_json::json!({
+                "id": id,
+                "timestamp": time.with_timezone(&Local).to_rfc3339(),
+                "host": host,
+                "model": model,
+                "message": message,
+                "role": role,
+                "snippet": snippet,
+            }));
+        }
+        match lib::output() {
+            lib::Output::Ndjson => items.iter().for_each(lib::print_json),
+            _ => lib::print_json(&serde_json::json!({ "matches": items })),
+        }
+        return Ok(());
+    }
+    if hits.is_empty() {
+        bail!("No messages match");
+    }
+    for (_, ts, _, role, snippet, model, host) in hits {
+        let header = format!("{}: {}@{} ({})", get_time_from_ts(ts)?, model, host, role);
+        lib::fmt_print(&header, lib::ContentType::Info, color);
+        lib::fmt_print(
+            &format!("{}\n\n", snippet),
+            lib::ContentType::AnswerBlock,
+            color,
+        );
+    }
+    Ok(())
+}
+
+// Each term as an FTS5 phrase, so punctuation is matched rather than parsed;
+// a trailing * keeps its prefix meaning
+fn fts_query(terms: &[String]) -> String {
+    let phrases: Vec<String> = terms
+        .iter()
+        .map(|t| match t.strip_suffix('*') {
+            Some(prefix) => format!("\"{}\"*", prefix.replace('"', "\"\"")),
+            None => format!("\"{}\"", t.replace('"', "\"\"")),
+        })
+        .collect();
+    phrases.join(" ")
+}
+
+// Milliseconds since the epoch for a date filter. A bare date starts at local
+// midnight, or ends at the next one for the end of a range
+fn parse_date(date: &str, end: bool) -> Result<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(time.timestamp_millis());
+    }
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid date \"{}\", use YYYY-MM-DD", date))?;
+    let day = match end {
+        true => day + Days::new(1),
+        false => day,
+    };
+    let midnight = Local
+        .from_local_datetime(&day.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .ok_or(anyhow!("Invalid date \"{}\"", date))?;
+    Ok(midnight.timestamp_millis())
+}
+
+// Best scoring message of each conversation as (conversation id, message,
+// score), best first and at most `limit` of them
+fn rank(scores: impl Iterator<Item = (i64, usize, f32)>, limit: usize) -> Vec<(i64, usize, f32)> {
+    let mut best: HashMap<i64, (usize, f32)> = HashMap::new();
+    for (id, i, score) in scores {
+        match best.get(&id) {
+            Some(&(_, s)) if s >= score => {}
+            _ => {
+                best.insert(id, (i, score));
+            }
+        }
+    }
+    let mut ranked: Vec<(i64, usize, f32)> =
+        best.into_iter().map(|(id, (i, s))| (id, i, s)).collect();
+    ranked.sort_by(|a, b| b.2.total_cmp(&a.2).then(b.0.cmp(&a.0)));
+    ranked.truncate(limit);
+    ranked
+}
+
+// Matched terms in bold; bold already in the message is dropped so the two
+// don't run together ("****answer****")
+fn highlight(snippet: &str) -> String {
+    snippet
+        .replace("**", "")
+        .replace([MATCH_START, MATCH_END], "**")
+}
+
+// Message on one line, for the list of matches
+fn snippet(content: &str) -> String {
+    content.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+pub fn print_conversation(conversation: &[Chat], color: bool) {
+    for chat in conversation {
         match chat.role.as_str() {
             "user" => {
+                if !chat.attachments.is_empty() {
+                    let attached = format!("\nAttached: {}", chat.attachments.join(", "));
+                    lib::fmt_print(&attached, lib::ContentType::Info, color);
+                }
+                if !chat.images.is_empty() {
+                    let images = format!("\nImages: {}", chat.images.join(", "));
+                    lib::fmt_print(&images, lib::ContentType::Info, color);
+                }
                 let content = format!("\n{}\n", &chat.content);
                 lib::fmt_print(&content, lib::ContentType::Exit, color)
             }
@@ -79,15 +525,11 @@ pub fn restore_conversation(color: bool) -> Result<(Option<String>, Vec<Chat>)>
             _ => lib::fmt_print(&chat.content, lib::ContentType::Info, color),
         }
     }
-    println!("\n");
-    Ok((
-        Some(entries[idx].context.clone().replace('\"', "")),
-        entries[idx].conversation.clone(),
-    ))
 }
 
 pub fn delete_conversations(color: bool) -> Result<()> {
-    let (entries, conversations) = get_conversation_entries()?;
+    let mut con = connect()?;
+    let (entries, conversations) = get_conversation_entries(&con)?;
     let idxs = match color {
         true => MultiSelect::with_theme(&ColorfulTheme::default())
             .with_prompt("Choose conversations to delete (spacebar to select/deselect)")
@@ -126,17 +568,26 @@ pub fn delete_conversations(color: bool) -> Result<()> {
     if !confirm {
         return Ok(());
     }
-    let con = Connection::open(lib::get_project_file(lib::ProjFiles::Data)?)?;
+    let tx = con.transaction()?;
     for i in idxs.into_iter() {
-        con.execute(DB_DELETE_STMT, [entries[i].timestamp])?;
+        tx.execute(DB_DELETE_STMT, [entries[i].id])?;
     }
+    tx.commit()?;
     lib::fmt_print("Conversations DELETED", lib::ContentType::Exit, color);
     lib::log(lib::LogLevel::Info, "db", "Conversations DELETED").unwrap();
     Ok(())
 }
 
 pub fn list_conversations(color: bool) -> Result<()> {
-    let (_, conversations) = get_conversation_entries()?;
+    let (entries, conversations) = get_conversation_entries(&connect()?)?;
+    if lib::output().is_json() {
+        let items: Vec<serde_json::Value> = entries.iter().map(list_item).collect::<Result<_>>()?;
+        match lib::output() {
+            lib::Output::Ndjson => items.iter().for_each(lib::print_json),
+            _ => lib::print_json(&serde_json::json!({ "conversations": items })),
+        }
+        return Ok(());
+    }
     lib::fmt_print("Previous conversations:", lib::ContentType::Exit, color);
     for conversation in conversations.iter() {
         lib::fmt_print(conversation, lib::ContentType::Info, color);
@@ -144,27 +595,160 @@ pub fn list_conversations(color: bool) -> Result<()> {
     Ok(())
 }
 
-fn get_conversation_entries() -> Result<(Vec<DBEntry>, Vec<String>)> {
+// Conversation list entry for the JSON output modes
+fn list_item(entry: &DBEntry) -> Result<serde_json::Value> {
+    let time = DateTime::from_timestamp_millis(entry.timestamp as i64)
+        .ok_or(anyhow!("Error parsing timestamp"))?;
+    let prompt = entry
+        .conversation
+        .iter()
+        .find(|c| c.role == "user")
+        .map(|c| c.content.as_str());
+    Ok(serde_json::json!({
+        "id": entry.id,
+        "timestamp": time.with_timezone(&Local).to_rfc3339(),
+        "host": entry.host,
+        "model": entry.model,
+        "system": entry.system,
+        "messages": entry.conversation.len(),
+        "prompt": prompt,
+    }))
+}
+
+/// Brings the DB schema up to date; run once at startup, before `connect`
+pub fn init() -> Result<()> {
+    let mut con = connect()?;
+    if let Some(version) = migrate(&mut con)? {
+        let msg = format!("Database schema migrated to version {}", version);
+        lib::log(lib::LogLevel::Info, "db", &msg)?;
+    }
+    Ok(())
+}
+
+pub fn connect() -> Result<Connection> {
     let con = Connection::open(lib::get_project_file(lib::ProjFiles::Data)?)?;
-    let mut stmt = match con.prepare(DB_SELECT_STMT) {
-        Ok(st) => st,
-        Err(_) => bail!("No responses saved"),
-    };
+    con.pragma_update(None, "foreign_keys", true)?;
+    Ok(con)
+}
+
+// Runs the migrations the DB has not had, each in a transaction of its own so
+// a failure leaves the DB as it was. Returns the new version if any ran
+fn migrate(con: &mut Connection) -> Result<Option<usize>> {
+    con.execute(DB_CREATE_VERSION_STMT, ())?;
+    let version: usize = con.query_row(DB_SELECT_VERSION_STMT, [], |row| row.get(0))?;
+    ensure!(
+        version <= MIGRATIONS.len(),
+        "Database schema version {} is newer than this rtwo supports ({})",
+        version,
+        MIGRATIONS.len()
+    );
+    if version == MIGRATIONS.len() {
+        return Ok(None);
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = con.transaction()?;
+        migration(&tx)?;
+        tx.execute(
+            DB_INSERT_VERSION_STMT,
+            (i + 1, Local::now().timestamp_millis()),
+        )?;
+        tx.commit()?;
+    }
+    Ok(Some(MIGRATIONS.len()))
+}
+
+// Version 1: conversations get an id, and their messages move out of the JSON
+// column into Messages
+fn normalize_conversations(con: &Connection) -> Result<()> {
+    let legacy: bool = con.query_row(DB_HAS_TABLE_STMT, ["Conversations"], |row| row.get(0))?;
+    if legacy {
+        con.execute(LEGACY_RENAME_STMT, ())?;
+    }
+    con.execute_batch(DB_SCHEMA_V1)?;
+    if !legacy {
+        return Ok(());
+    }
+    let mut stmt = con.prepare(LEGACY_SELECT_STMT)?;
     let rows = stmt.query_map([], |row| {
-        let convo_str: String = row.get(3)?;
-        let conversation: Vec<Chat> = serde_json::from_str(&convo_str).unwrap();
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    })?;
+    for row in rows {
+        let (timestamp, host, model, convo, context) = row?;
+        let conversation: Vec<Chat> = serde_json::from_str(&convo)
+            .map_err(|e| anyhow!("Conversation saved at {} -> {}", timestamp, e))?;
+        // The context was saved as a quoted debug string ("\"[1, 2]\""), or "[]"
+        let context = serde_json::from_str::<String>(&context).unwrap_or(context);
+        let context: Vec<i64> = serde_json::from_str(&context).unwrap_or_default();
+        let context = serde_json::to_string(&context)?;
+        let id = insert_conversation(con, timestamp, &host, &model, &context, None)?;
+        for (i, chat) in conversation.into_iter().enumerate() {
+            // Messages were saved at the end of the conversation, and answers
+            // came from its model
+            let chat = Chat {
+                timestamp: Some(timestamp),
+                model: (chat.role == "assistant").then(|| model.clone()),
+                ..chat
+            };
+            insert_message(con, id, i, &chat)?;
+        }
+    }
+    drop(stmt);
+    con.execute(LEGACY_DROP_STMT, ())?;
+    Ok(())
+}
+
+fn get_conversation_entries(con: &Connection) -> Result<(Vec<DBEntry>, Vec<String>)> {
+    let mut messages: HashMap<i64, Vec<Chat>> = HashMap::new();
+    let mut stmt = con.prepare(DB_SELECT_MESSAGES_STMT)?;
+    let rows = stmt.query_map([], |row| {
+        // Path lists are JSON, NULL when empty
+        let paths = |idx: usize| -> rusqlite::Result<Vec<String>> {
+            let json: Option<String> = row.get(idx)?;
+            Ok(json
+                .and_then(|j| serde_json::from_str(&j).ok())
+                .unwrap_or_default())
+        };
+        let chat = Chat {
+            role: row.get(1)?,
+            content: row.get(2)?,
+            attachments: paths(3)?,
+            images: paths(4)?,
+            timestamp: row.get(5)?,
+            model: row.get(6)?,
+            prompt_eval_count: row.get(7)?,
+            eval_count: row.get(8)?,
+            eval_duration: row.get(9)?,
+            ..Default::default()
+        };
+        Ok((row.get::<_, i64>(0)?, chat))
+    })?;
+    for row in rows {
+        let (id, chat) = row?;
+        messages.entry(id).or_default().push(chat);
+    }
+    let mut stmt = con.prepare(DB_SELECT_STMT)?;
+    let rows = stmt.query_map([], |row| {
+        let id: i64 = row.get(0)?;
         Ok(DBEntry {
-            timestamp: row.get(0)?,
-            host: row.get(1)?,
-            model: row.get(2)?,
-            conversation,
+            id,
+            timestamp: row.get(1)?,
+            host: row.get(2)?,
+            model: row.get(3)?,
+            conversation: messages.remove(&id).unwrap_or_default(),
             context: row.get(4)?,
+            system: row.get(5)?,
         })
     })?;
     let mut entries: Vec<DBEntry> = vec![];
     let mut conversations: Vec<String> = vec![];
     for row in rows {
-        let entry = row?.clone();
+        let entry = row?;
         let ts = get_time_from_ts(entry.timestamp)?;
         let len_context = entry.context.matches(',').collect::<Vec<&str>>().len() + 1;
         conversations.push(format!(
@@ -172,10 +756,13 @@ fn get_conversation_entries() -> Result<(Vec<DBEntry>, Vec<String>)> {
             ts,
             entry.model,
             entry.host,
-            entry.conversation.first().unwrap().content,
+            entry
+                .conversation
+                .first()
+                .map_or("", |c| c.content.as_str()),
             len_context
         ));
-        entries.push(entry.clone());
+        entries.push(entry);
     }
     if entries.is_empty() {
         bail!("No responses saved");
@@ -189,3 +776,216 @@ fn get_time_from_ts(ts: u64) -> Result<String> {
     };
     Err(anyhow!("Error parsing timestamp"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_keeps_best_message_per_conversation() {
+        let scores = [(1, 0, 0.2), (1, 3, 0.9), (2, 1, 0.5), (3, 0, 0.1)];
+        let ranked = rank(scores.into_iter(), 2);
+        assert_eq!(ranked, vec![(1, 3, 0.9), (2, 1, 0.5)]);
+    }
+
+    #[test]
+    fn fts_query_quotes_terms() {
+        let terms = [
+            "foo-bar".to_string(),
+            "say \"hi\"".to_string(),
+            "migr*".to_string(),
+        ];
+        assert_eq!(fts_query(&terms), r#""foo-bar" "say ""hi""" "migr"*"#);
+    }
+
+    // In-memory DB from `sql`, migrated as `connect` would
+    fn migrated(sql: &str) -> Connection {
+        let mut con = Connection::open_in_memory().unwrap();
+        con.pragma_update(None, "foreign_keys", true).unwrap();
+        con.execute_batch(sql).unwrap();
+        assert_eq!(migrate(&mut con).unwrap(), Some(MIGRATIONS.len()));
+        con
+    }
+
+    fn count(con: &Connection, table: &str) -> i64 {
+        let sql = format!("SELECT COUNT(*) FROM {}", table);
+        con.query_row(&sql, [], |row| row.get(0)).unwrap()
+    }
+
+    fn grep(con: &Connection, terms: &[&str], model: Option<&str>) -> Vec<(i64, usize, String)> {
+        let terms: Vec<String> = terms.iter().map(|t| t.to_string()).collect();
+        let params = (
+            fts_query(&terms),
+            model,
+            None::<&str>,
+            None::<i64>,
+            None::<i64>,
+            10,
+        );
+        con.prepare(DB_GREP_STMT)
+            .unwrap()
+            .query_map(params, |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(2)?,
+                    highlight(&row.get::<_, String>(4)?),
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_legacy_conversations() {
+        let con = migrated(include_str!("fixtures/legacy.sql"));
+        let (entries, labels) = get_conversation_entries(&con).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(labels.len(), 3);
+
+        let first = &entries[0];
+        assert_eq!((first.id, first.timestamp), (1, 1714561200000));
+        assert_eq!(first.host, "localhost:11434");
+        assert_eq!(first.model, "llama3:latest");
+        assert_eq!(first.system, None);
+        assert_eq!(first.context, "[1,2,3]");
+        let roles: Vec<&str> = first.conversation.iter().map(|c| c.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+        assert!(first.conversation[0]
+            .content
+            .ends_with("```rust\nfn main() {}\n```"));
+        assert_eq!(first.conversation[0].model, None);
+        assert_eq!(
+            first.conversation[1].model.as_deref(),
+            Some("llama3:latest")
+        );
+        assert!(first
+            .conversation
+            .iter()
+            .all(|c| c.timestamp == Some(1714561200000)));
+        // Contexts are plain JSON arrays from now on
+        assert_eq!(entries[1].context, "[4,5]");
+        assert_eq!(entries[2].context, "[]");
+
+        // Messages are searchable, and the index tables are there to fill
+        assert_eq!(
+            grep(&con, &["migration"], None),
+            [(1, 1, "The **migrations** table is missing.".to_string())]
+        );
+        assert_eq!(count(&con, "MessageEmbeddings"), 0);
+        assert_eq!(count(&con, "Chunks"), 0);
+        let exists: bool = con
+            .query_row(DB_HAS_TABLE_STMT, ["LegacyConversations"], |row| row.get(0))
+            .unwrap();
+        assert!(!exists, "LegacyConversations was not dropped");
+    }
+
+    #[test]
+    fn migrate_runs_once_and_refuses_newer_schemas() {
+        let mut con = migrated("");
+        assert_eq!(count(&con, "Conversations"), 0);
+        assert_eq!(migrate(&mut con).unwrap(), None);
+        let version: usize = con
+            .query_row(DB_SELECT_VERSION_STMT, [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        con.execute(DB_INSERT_VERSION_STMT, (MIGRATIONS.len() + 1, 0))
+            .unwrap();
+        assert!(migrate(&mut con).is_err());
+    }
+
+    #[test]
+    fn grep_filters_and_deletes_follow_messages() {
+        let con = migrated(include_str!("fixtures/legacy.sql"));
+        assert_eq!(grep(&con, &["table"], Some("llama3")).len(), 1);
+        assert!(grep(&con, &["table"], Some("llava")).is_empty());
+        assert!(grep(&con, &["table", "cat"], None).is_empty());
+        con.execute(
+            DB_INSERT_EMBEDDING_STMT,
+            (1, "nomic-embed-text:latest", kb::to_blob(&[1.0])),
+        )
+        .unwrap();
+        con.execute(DB_DELETE_STMT, [1]).unwrap();
+        assert_eq!(count(&con, "Messages"), 4);
+        assert_eq!(count(&con, "MessageEmbeddings"), 0);
+        assert!(grep(&con, &["table"], None).is_empty());
+        assert_eq!(grep(&con, &["cat"], None).len(), 1);
+    }
+
+    #[test]
+    fn highlight_replaces_bold_in_the_message() {
+        let con = migrated("");
+        con.execute(
+            DB_INSERT_STMT,
+            (1, "localhost:11434", "llama3:latest", "[]", None::<&str>),
+        )
+        .unwrap();
+        con.execute(
+            DB_INSERT_MESSAGE_STMT,
+            (
+                1,
+                0,
+                1,
+                "assistant",
+                "Chat **answer** and *more*",
+                None::<&str>,
+                "[]",
+                "[]",
+                None::<u64>,
+                None::<u64>,
+                None::<u64>,
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            grep(&con, &["answer"], None),
+            [(1, 0, "Chat **answer** and *more*".to_string())]
+        );
+        assert_eq!(
+            grep(&con, &["more"], None)[0].2,
+            "Chat answer and ***more***"
+        );
+    }
+
+    #[test]
+    fn saving_again_replaces_the_conversation() {
+        let con = migrated("");
+        let chat = |role: &str, content: &str| Chat {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let mut conversation = vec![chat("user", "first"), chat("assistant", "one")];
+        let write = |id, conversation: &[Chat]| {
+            write_conversation(
+                &con,
+                id,
+                conversation,
+                "localhost:11434",
+                "llama3:latest",
+                "[]",
+                None,
+            )
+            .unwrap()
+        };
+        let id = write(None, &conversation);
+        conversation.extend([chat("user", "second"), chat("assistant", "two")]);
+        assert_eq!(write(Some(id), &conversation), id);
+        assert_eq!(count(&con, "Conversations"), 1);
+        assert_eq!(count(&con, "Messages"), 4);
+        assert_eq!(grep(&con, &["second"], None).len(), 1);
+        // A conversation deleted since it was restored is saved anew
+        con.execute(DB_DELETE_STMT, [id]).unwrap();
+        write(Some(id), &conversation[..2]);
+        assert_eq!(count(&con, "Conversations"), 1);
+        assert_eq!(count(&con, "Messages"), 2);
+    }
+
+    #[test]
+    fn snippet_joins_lines() {
+        assert_eq!(
+            snippet("  fix the\n\nbuild  script "),
+            "fix the build script"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Cmd, Config, Editor, EventHandler, Helper, KeyCode, KeyEvent, Modifiers};
+
+const MULTILINE_DELIM: &str = "\"\"\"";
+const MAX_HISTORY: usize = 1000;
+const DEFAULT_EDITOR: &str = "vi";
+
+/// Line editor for prompts with persistent history (Ctrl-R to search).
+/// Alt+Enter inserts a newline; input opened with `"""` continues until a
+/// closing `"""`. Pasted text is inserted as-is, newlines included.
+pub struct LineEditor {
+    editor: Editor<PromptHelper, FileHistory>,
+    history_file: String,
+    color: bool,
+}
+
+struct PromptHelper {
+    color: bool,
+}
+
+impl LineEditor {
+    pub fn new(color: bool) -> Result<Self> {
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY)?
+            .history_ignore_dups(true)?
+            .auto_add_history(false)
+            .bracketed_paste(true)
+            .build();
+        let mut editor: Editor<PromptHelper, FileHistory> = Editor::with_config(config)?;
+        editor.set_helper(Some(PromptHelper { color }));
+        editor.bind_sequence(
+            KeyEvent(KeyCode::Enter, Modifiers::ALT),
+            EventHandler::Simple(Cmd::Newline),
+        );
+        let history_file = lib::get_project_file(lib::ProjFiles::History)?;
+        // Missing on first run
+        let _ = editor.load_history(&history_file);
+        Ok(LineEditor {
+            editor,
+            history_file,
+            color,
+        })
+    }
+
+    /// Read one prompt. Returns `None` on Ctrl-D; Ctrl-C discards the line and
+    /// returns an empty prompt.
+    pub fn read(&mut self, prompt: &str) -> Result<Option<String>> {
+        // Highlighter only adds color, so the raw prompt must have the same width
+        let prompt = match self.color {
+            true => format!("? {} › ", prompt),
+            false => format!("{}: ", prompt),
+        };
+        let line = match self.editor.readline(&prompt) {
+            Ok(l) => l,
+            Err(ReadlineError::Interrupted) => return Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            self.editor.add_history_entry(line.as_str())?;
+            if let Err(e) = self.editor.append_history(&self.history_file) {
+                let msg = format!("Failed to write prompt history -> {}", e);
+                lib::log(lib::LogLevel::Error, "input", &msg)?;
+            }
+        }
+        Ok(Some(strip_multiline(&line)))
+    }
+}
+
+/// Open `$VISUAL`/`$EDITOR` (falling back to vi) on a temp file holding
+/// `initial`. Returns the saved buffer, or `None` if it was left empty.
+pub fn compose_in_editor(initial: &str) -> Result<Option<String>> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or(DEFAULT_EDITOR.to_string());
+    let mut file = tempfile::Builder::new()
+        .prefix("rtwo-prompt-")
+        .suffix(".md")
+        .tempfile()?;
+    file.write_all(initial.as_bytes())?;
+    file.flush()?;
+    // Allow editors with arguments, e.g. "code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or(anyhow!("Invalid editor"))?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(file.path())
+        .status()
+        .map_err(|e| anyhow!("Could not run editor \"{}\" -> {}", editor, e))?;
+    if !status.success() {
+        bail!("Editor \"{}\" exited with {}", editor, status);
+    }
+    let prompt = fs::read_to_string(file.path())?;
+    if prompt.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(prompt.trim_end().to_string()))
+}
+
+impl Helper for PromptHelper {}
+
+impl Completer for PromptHelper {
+    type Candidate = String;
+}
+
+impl Hinter for PromptHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PromptHelper {
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        default: bool,
+    ) -> Cow<'b, str> {
+        let _ = default;
+        if !self.color {
+            return Cow::Borrowed(prompt);
+        }
+        let text = prompt.trim_start_matches("? ").trim_end_matches(" › ");
+        Cow::Owned(format!(
+            "{} {} {} ",
+            "?".yellow(),
+            text.bold(),
+            "›".dimmed()
+        ))
+    }
+}
+
+impl Validator for PromptHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        match is_open_multiline(ctx.input()) {
+            true => Ok(ValidationResult::Incomplete),
+            false => Ok(ValidationResult::Valid(None)),
+        }
+    }
+}
+
+// Input opened with `"""` continues until it also ends with one
+fn is_open_multiline(input: &str) -> bool {
+    let input = input.trim();
+    input.starts_with(MULTILINE_DELIM)
+        && (input.len() < 2 * MULTILINE_DELIM.len() || !input.ends_with(MULTILINE_DELIM))
+}
+
+// `"""` blocks are sent without their delimiters
+fn strip_multiline(line: &str) -> String {
+    let trimmed = line.trim();
+    match trimmed
+        .strip_prefix(MULTILINE_DELIM)
+        .and_then(|l| l.strip_suffix(MULTILINE_DELIM))
+    {
+        Some(inner) => inner.trim_matches('\n').to_string(),
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiline_continues_until_closed() {
+        assert!(is_open_multiline("\"\"\""));
+        assert!(is_open_multiline("  \"\"\"first line"));
+        assert!(is_open_multiline("\"\"\"first\nsecond"));
+        assert!(is_open_multiline("\"\"\"\"\""));
+        assert!(!is_open_multiline("\"\"\"first\nsecond\n\"\"\"  "));
+        assert!(!is_open_multiline("\"\"\"\"\"\""));
+        assert!(!is_open_multiline("\"\"\"one line\"\"\""));
+        // Only an opening delimiter starts a block
+        assert!(!is_open_multiline("say \"\"\""));
+        assert!(!is_open_multiline(""));
+    }
+
+    #[test]
+    fn multiline_delimiters_are_stripped() {
+        assert_eq!(
+            strip_multiline("\"\"\"\nfirst\n  second\n\"\"\""),
+            "first\n  second"
+        );
+        assert_eq!(strip_multiline(" \"\"\"one line\"\"\" "), "one line");
+        assert_eq!(strip_multiline("\"\"\"\"\"\""), "");
+        // Anything else is kept as typed
+        assert_eq!(strip_multiline("  why?\n"), "  why?\n");
+        assert_eq!(strip_multiline("\"\"\"unclosed"), "\"\"\"unclosed");
+        assert_eq!(strip_multiline("say \"\"\"x\"\"\""), "say \"\"\"x\"\"\"");
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use lib::client::OllamaClient;
+use rusqlite::Connection;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{attach, db, ollama};
+
+// Lines in a chunk, and lines a chunk repeats from the end of the one before
+const CHUNK_LINES: usize = 40;
+const CHUNK_OVERLAP: usize = 5;
+// Chunks also end before this many bytes, for files with long lines
+const CHUNK_MAX_BYTES: usize = 4000;
+
+const KB_SELECT_FILES_STMT: &str =
+    "SELECT path, mtime, hash, model FROM IndexedFiles WHERE kb=(?1)";
+const KB_SELECT_MODELS_STMT: &str = "SELECT DISTINCT model FROM IndexedFiles WHERE kb=(?1)";
+const KB_UPSERT_FILE_STMT: &str = "INSERT OR REPLACE INTO IndexedFiles (kb, path, mtime, hash, model) VALUES (?1, ?2, ?3, ?4, ?5)";
+const KB_DELETE_FILE_STMT: &str = "DELETE FROM IndexedFiles WHERE kb=(?1) AND path=(?2)";
+const KB_INSERT_CHUNK_STMT: &str = "INSERT INTO Chunks (kb, path, start_line, end_line, content, embedding) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+const KB_SELECT_CHUNKS_STMT: &str =
+    "SELECT path, start_line, end_line, content, embedding FROM Chunks WHERE kb=(?1)";
+const KB_DELETE_CHUNKS_STMT: &str = "DELETE FROM Chunks WHERE kb=(?1) AND path=(?2)";
+const KB_CLEAR_FILES_STMT: &str = "DELETE FROM IndexedFiles WHERE kb=(?1)";
+const KB_CLEAR_CHUNKS_STMT: &str = "DELETE FROM Chunks WHERE kb=(?1)";
+
+/// A retrieved chunk, cited in the prompt by its path and lines
+#[derive(Clone)]
+pub struct Source {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub content: String,
+    pub score: f32,
+}
+
+impl Source {
+    pub fn label(&self) -> String {
+        format!("{}:{}-{}", self.path, self.start_line, self.end_line)
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct IndexStats {
+    pub indexed: usize,
+    pub chunks: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub skipped: Vec<String>,
+}
+
+// A new or changed file, waiting for its chunks to be embedded
+struct Pending {
+    path: String,
+    mtime: i64,
+    hash: String,
+    chunks: Vec<(usize, usize, String)>,
+}
+
+/// Chunks and embeds the text files below `dir` with `model`. Files whose
+/// mtime is unchanged are skipped, and files whose content hash is unchanged
+/// only have their mtime updated. Files no longer on disk, or no longer
+/// readable, are dropped. A different model rebuilds the whole index, since
+/// vectors from two models cannot be compared.
+pub fn index(
+    dir: &str,
+    exclude: &[String],
+    model: &str,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<IndexStats> {
+    let root = fs::canonicalize(dir).map_err(|e| anyhow!("{} -> {}", dir, e))?;
+    ensure!(root.is_dir(), "{} is not a directory", dir);
+    let kb = root.to_string_lossy().to_string();
+    let exclude: Vec<glob::Pattern> = exclude
+        .iter()
+        .map(|p| glob::Pattern::new(p).map_err(|e| anyhow!("Invalid pattern \"{}\" -> {}", p, e)))
+        .collect::<Result<_>>()?;
+    let mut con = db::connect()?;
+    let mut known = indexed_files(&con, &kb)?;
+    let rebuild = known.values().any(|f| f.model != model);
+    if rebuild {
+        known.clear();
+    }
+    let mut stats = IndexStats::default();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut pending: Vec<Pending> = vec![];
+    let mut touched: Vec<(String, i64, String)> = vec![];
+    for file in attach::expand(&kb)? {
+        let path = file
+            .strip_prefix(&root)
+            .unwrap_or(&file)
+            .to_string_lossy()
+            .to_string();
+        if exclude.iter().any(|p| p.matches(&path)) {
+            continue;
+        }
+        let mtime = modified(&file)?;
+        let prev = known.get(&path);
+        if prev.is_some_and(|f| f.mtime == mtime) {
+            seen.insert(path);
+            stats.unchanged += 1;
+            continue;
+        }
+        let content = match attach::read(&file) {
+            Ok(c) => c,
+            Err(e) => {
+                stats.skipped.push(format!("{} ({})", path, e));
+                continue;
+            }
+        };
+        seen.insert(path.clone());
+        let hash = format!("{:x}", Sha256::digest(content.as_bytes()));
+        if prev.is_some_and(|f| f.hash == hash) {
+            touched.push((path, mtime, hash));
+            stats.unchanged += 1;
+            continue;
+        }
+        pending.push(Pending {
+            path,
+            mtime,
+            hash,
+            chunks: chunk(&content),
+        });
+    }
+    let texts: Vec<String> = pending
+        .iter()
+        .flat_map(|p| {
+            p.chunks
+                .iter()
+                .map(|(_, _, text)| format!("{}\n{}", p.path, text))
+        })
+        .collect();
+    let vectors = ollama::embed_texts(&texts, model, client, conf)?;
+    let tx = con.transaction()?;
+    if rebuild {
+        tx.execute(KB_CLEAR_CHUNKS_STMT, [&kb])?;
+        tx.execute(KB_CLEAR_FILES_STMT, [&kb])?;
+    }
+    let mut vectors = vectors.into_iter();
+    for p in &pending {
+        tx.execute(KB_DELETE_CHUNKS_STMT, (&kb, &p.path))?;
+        for ((start, end, text), vector) in p.chunks.iter().zip(vectors.by_ref()) {
+            tx.execute(
+                KB_INSERT_CHUNK_STMT,
+                (&kb, &p.path, start, end, text, to_blob(&vector)),
+            )?;
+        }
+        tx.execute(KB_UPSERT_FILE_STMT, (&kb, &p.path, p.mtime, &p.hash, model))?;
+        stats.indexed += 1;
+        stats.chunks += p.chunks.len();
+    }
+    for (path, mtime, hash) in &touched {
+        tx.execute(KB_UPSERT_FILE_STMT, (&kb, path, mtime, hash, model))?;
+    }
+    for path in known.keys().filter(|p| !seen.contains(*p)) {
+        tx.execute(KB_DELETE_CHUNKS_STMT, (&kb, path))?;
+        tx.execute(KB_DELETE_FILE_STMT, (&kb, path))?;
+        stats.removed += 1;
+    }
+    tx.commit()?;
+    let msg = format!(
+        "Indexed {}: {} files ({} chunks), {} unchanged, {} removed",
+        kb, stats.indexed, stats.chunks, stats.unchanged, stats.removed
+    );
+    lib::log(lib::LogLevel::Info, "kb", &msg)?;
+    Ok(stats)
+}
+
+/// The `top_k` chunks most similar to `prompt` across the indexed directories,
+/// best first. Each index is searched with the model it was built with.
+pub fn retrieve(
+    prompt: &str,
+    dirs: &[String],
+    top_k: usize,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Vec<Source>> {
+    let con = db::connect()?;
+    let mut queries: HashMap<String, Vec<f32>> = HashMap::new();
+    let mut sources: Vec<Source> = vec![];
+    for dir in dirs {
+        let kb = fs::canonicalize(dir)
+            .map(|p| p.to_string_lossy().to_string())
+            .map_err(|e| anyhow!("{} -> {}", dir, e))?;
+        let models: Vec<String> = con
+            .prepare(KB_SELECT_MODELS_STMT)?
+            .query_map([&kb], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let model = match models.as_slice() {
+            [] => bail!(
+                "No index for {}, create one with \"rtwo index {}\"",
+                dir,
+                dir
+            ),
+            [model] => model.clone(),
+            _ => bail!(
+                "Index for {} mixes embedding models ({}), rebuild it with \"rtwo index {}\"",
+                dir,
+                models.join(", "),
+                dir
+            ),
+        };
+        if !queries.contains_key(&model) {
+            let mut vectors = ollama::embed_texts(&[prompt.to_string()], &model, client, conf)?;
+            queries.insert(model.clone(), vectors.remove(0));
+        }
+        let query = &queries[&model];
+        let mut stmt = con.prepare(KB_SELECT_CHUNKS_STMT)?;
+        let rows = stmt.query_map([&kb], |row| {
+            let blob: Vec<u8> = row.get(4)?;
+            let source = Source {
+                path: row.get(0)?,
+                start_line: row.get(1)?,
+                end_line: row.get(2)?,
+                content: row.get(3)?,
+                score: 0.0,
+            };
+            Ok((source, from_blob(&blob)))
+        })?;
+        for row in rows {
+            let (mut source, vector) = row?;
+            source.score =
+                cosine(query, &vector).map_err(|e| anyhow!("{} in the index for {}", e, dir))?;
+            sources.push(source);
+        }
+    }
+    sources.sort_by(|a, b| b.score.total_cmp(&a.score));
+    sources.truncate(top_k);
+    Ok(sources)
+}
+
+/// Prompt with the numbered sources ahead of it, asking for citations
+pub fn compose(prompt: &str, sources: &[Source]) -> String {
+    if sources.is_empty() {
+        return prompt.to_string();
+    }
+    let mut composed = String::from(
+        "Answer using the numbered sources below where they are relevant, citing them like [1]. \
+         Say so if they do not contain the answer.\n\n",
+    );
+    for (i, s) in sources.iter().enumerate() {
+        let label = format!("[{}] {}", i + 1, s.label());
+        composed.push_str(&attach::fenced(&label, &s.path, &s.content));
+        composed.push_str("\n\n");
+    }
+    composed.push_str(&format!("Question: {}", prompt));
+    composed
+}
+
+/// Lists the sources sent with a prompt, numbered as the answer cites them
+pub fn print_sources(sources: &[Source], color: bool) {
+    if sources.is_empty() {
+        return;
+    }
+    let list: Vec<String> = sources
+        .iter()
+        .enumerate()
+        .map(|(i, s)| format!("[{}] {} ({:.2})", i + 1, s.label(), s.score))
+        .collect();
+    let msg = format!("Sources:\n{}", list.join("\n"));
+    lib::fmt_print(&msg, lib::ContentType::Info, color);
+}
+
+struct IndexedFile {
+    mtime: i64,
+    hash: String,
+    model: String,
+}
+
+fn indexed_files(con: &Connection, kb: &str) -> Result<HashMap<String, IndexedFile>> {
+    let mut stmt = con.prepare(KB_SELECT_FILES_STMT)?;
+    let rows = stmt.query_map([kb], |row| {
+        Ok((
+            row.get(0)?,
+            IndexedFile {
+                mtime: row.get(1)?,
+                hash: row.get(2)?,
+                model: row.get(3)?,
+            },
+        ))
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+// Overlapping runs of lines as (first line, last line, text), numbered from 1
+fn chunk(content: &str) -> Vec<(usize, usize, String)> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start;
+        let mut bytes = 0;
+        while end < lines.len()
+            && end - start < CHUNK_LINES
+            && (end == start || bytes + lines[end].len() < CHUNK_MAX_BYTES)
+        {
+            bytes += lines[end].len() + 1;
+            end += 1;
+        }
+        let text = lines[start..end].join("\n");
+        if !text.trim().is_empty() {
+            chunks.push((start + 1, end, text));
+        }
+        if end == lines.len() {
+            break;
+        }
+        start = end.saturating_sub(CHUNK_OVERLAP).max(start + 1);
+    }
+    chunks
+}
+
+fn modified(path: &Path) -> Result<i64> {
+    let mtime = fs::metadata(path)?.modified()?;
+    Ok(mtime.duration_since(UNIX_EPOCH)?.as_nanos() as i64)
+}
+
+/// Cosine similarity, 0 when either vector is all zeros. Vectors of different
+/// lengths come from different models and are an error
+pub fn cosine(a: &[f32], b: &[f32]) -> Result<f32> {
+    ensure!(
+        a.len() == b.len(),
+        "Embedding sizes differ ({} and {})",
+        a.len(),
+        b.len()
+    );
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    Ok(match norm(a) * norm(b) {
+        n if n > 0.0 => dot / n,
+        _ => 0.0,
+    })
+}
+
+/// Vector as stored in SQLite: little-endian f32s
+pub fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+pub fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_overlap_and_cover_every_line() {
+        let content: String = (1..=100).map(|n| format!("line {}\n", n)).collect();
+        let chunks = chunk(&content);
+        assert_eq!(chunks[0].0, 1);
+        assert_eq!(chunks[0].1, CHUNK_LINES);
+        assert_eq!(chunks[1].0, CHUNK_LINES - CHUNK_OVERLAP + 1);
+        assert_eq!(chunks.last().unwrap().1, 100);
+        for pair in chunks.windows(2) {
+            assert!(pair[1].0 <= pair[0].1 + 1);
+        }
+        assert!(chunk("\n\n  \n").is_empty());
+    }
+
+    #[test]
+    fn long_lines_end_chunks_early() {
+        let line = "x".repeat(CHUNK_MAX_BYTES / 2);
+        let content = [line.as_str(); 6].join("\n");
+        let chunks = chunk(&content);
+        assert!(chunks.iter().all(|(start, end, _)| end - start < 2));
+        assert_eq!(chunks.last().unwrap().1, 6);
+    }
+
+    #[test]
+    fn blobs_round_trip_and_cosine_ranks() {
+        let v = vec![0.5, -1.25, 3e-7, f32::MAX];
+        assert_eq!(from_blob(&to_blob(&v)), v);
+        let q = [1.0, 0.0];
+        assert!(cosine(&q, &[2.0, 0.1]).unwrap() > cosine(&q, &[1.0, 1.0]).unwrap());
+        assert_eq!(cosine(&q, &[0.0, 0.0]).unwrap(), 0.0);
+        assert!(cosine(&q, &[1.0, 0.0, 0.0]).is_err());
+    }
+}
@@ -1,17 +1,26 @@
+use std::collections::HashMap;
+use std::fs;
 use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::OnceLock;
 
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bat::PrettyPrinter;
 use chrono::Local;
 use clap::ArgMatches;
 use colored::Colorize;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use directories::ProjectDirs;
+use jsonschema::JSONSchema;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use toml::to_string;
 
+pub mod client;
+
+use client::ModelOptions;
+
 struct Project<T: AsRef<str>> {
     qualifier: T,
     org: T,
@@ -26,15 +35,142 @@ pub struct Config {
     pub verbose: bool, // Verbose output following response
     pub color: bool,   // Color output
     pub save: bool,    // Autosave conversation
+    #[serde(default = "default_true")]
+    pub chat: bool, // Send message history to /api/chat instead of context to /api/generate
+    #[serde(default, skip_serializing_if = "ModelOptions::is_empty")]
+    pub options: ModelOptions, // [options] sent with every request
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub model_options: HashMap<String, ModelOptions>, // [model_options."name"] overrides per model
+    #[serde(skip)]
+    pub cli_options: ModelOptions, // Options from args (override config)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>, // System prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>, // How long models stay loaded after a request ("10m", seconds, -1 for ever)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_model: Option<String>, // Model for embeddings (embed)
+    #[serde(default)]
+    pub auto_pull: bool, // Pull the model when it is not on the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_pull_max_size: Option<f64>, // Largest model (GB) auto_pull downloads (default 10, 0 for no limit)
+    #[serde(skip)]
+    pub format: Option<serde_json::Value>, // "json" or a JSON schema for structured answers (args only)
+    #[serde(skip)]
+    pub format_retries: u32, // Times to re-prompt when a structured answer is invalid (args only)
+    #[serde(skip)]
+    pub kb: Vec<String>, // Indexed directories searched for sources with each prompt (args only)
+    #[serde(skip)]
+    pub kb_top_k: usize, // Sources added to each prompt (args only)
+}
+
+impl Config {
+    /// Options for the current model: [options], then the model's override table, then args.
+    /// A table for "llama3" applies to "llama3:latest" and the other way round, as the
+    /// model name resolves; one for the exact name wins.
+    pub fn effective_options(&self) -> ModelOptions {
+        let mut opts = self.options.clone();
+        let model_opts = self
+            .model_options
+            .get(&self.model)
+            .or_else(|| self.model_options.get(&latest_alias(&self.model)?));
+        if let Some(model_opts) = model_opts {
+            opts = opts.merge(model_opts);
+        }
+        opts.merge(&self.cli_options)
+    }
+
+    /// `keep_alive` as sent to Ollama: a number of seconds, or a duration string
+    pub fn keep_alive(&self) -> Option<serde_json::Value> {
+        self.keep_alive
+            .as_deref()
+            .and_then(|k| parse_keep_alive(k).ok())
+    }
+
+    /// Largest model auto_pull downloads, in bytes; `None` when unlimited
+    pub fn auto_pull_limit(&self) -> Option<u64> {
+        match self.auto_pull_max_size.unwrap_or(AUTO_PULL_MAX_SIZE) {
+            0.0 => None,
+            gb => Some((gb * 1e9) as u64),
+        }
+    }
 }
 
 pub enum ContentType {
     Error,
     Info,
     Answer,
+    AnswerBlock,
+    Json,
     Exit,
 }
 
+/// How results are printed: text for people, or JSON for scripts.
+/// In the JSON modes stdout only carries JSON; other messages go to stderr.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Output {
+    #[default]
+    Plain,
+    Json,   // One JSON document per result
+    Ndjson, // One JSON object per line (list items, answer tokens)
+}
+
+/// Stable codes for errors in the JSON output modes
+pub enum ErrorCode {
+    Config,
+    Connection,
+    ModelNotFound,
+    Pull,
+    Delete,
+    Show,
+    Copy,
+    Create,
+    Push,
+    Load,
+    Unload,
+    Ps,
+    Embed,
+    Index,
+    History,
+    Generate,
+    Database,
+    Input,
+    Attach,
+    Other,
+}
+
+impl Output {
+    pub fn is_json(&self) -> bool {
+        *self != Output::Plain
+    }
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Config => "config",
+            ErrorCode::Connection => "connection",
+            ErrorCode::ModelNotFound => "model_not_found",
+            ErrorCode::Pull => "pull_failed",
+            ErrorCode::Delete => "delete_failed",
+            ErrorCode::Show => "show_failed",
+            ErrorCode::Copy => "copy_failed",
+            ErrorCode::Create => "create_failed",
+            ErrorCode::Push => "push_failed",
+            ErrorCode::Load => "load_failed",
+            ErrorCode::Unload => "unload_failed",
+            ErrorCode::Ps => "ps_failed",
+            ErrorCode::Embed => "embed_failed",
+            ErrorCode::Index => "index_failed",
+            ErrorCode::History => "history_failed",
+            ErrorCode::Generate => "generate_failed",
+            ErrorCode::Database => "database",
+            ErrorCode::Input => "input",
+            ErrorCode::Attach => "attach",
+            ErrorCode::Other => "error",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum LogLevel {
     Debug,
@@ -46,6 +182,8 @@ pub enum ProjFiles {
     Conf,
     Data,
     Log,
+    History,
+    Personas,
 }
 
 const PROJECT: Project<&'static str> = Project {
@@ -57,6 +195,13 @@ const PROJECT: Project<&'static str> = Project {
 const LOG_FILE: &str = "rtwo.log";
 const CONF_FILE: &str = "rtwo.toml";
 const DB_FILE: &str = "rtwo.db";
+const HISTORY_FILE: &str = "history.txt";
+const PERSONA_DIR: &str = "personas";
+const PERSONA_EXTS: &[&str] = &["md", "txt"];
+// GB auto_pull downloads at most when auto_pull_max_size is not set
+const AUTO_PULL_MAX_SIZE: f64 = 10.0;
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
 
 pub fn log(lvl: LogLevel, descriptor: &str, msg: &str) -> Result<()> {
     let log_msg = format!("{:?} {:?} [{}]: {}\n", Local::now(), lvl, descriptor, msg);
@@ -77,6 +222,10 @@ pub fn setup_file_struct() -> Result<()> {
         if !proj.config_dir().exists() {
             create_dir_all(proj.config_dir())?;
         }
+        let persona_dir = proj.config_dir().join(PERSONA_DIR);
+        if !persona_dir.exists() {
+            create_dir_all(persona_dir)?;
+        }
         let conf_file = format!("{}/{}", proj.config_dir().to_str().unwrap(), CONF_FILE);
         if !Path::new(&conf_file).exists() {
             println!("Configuration not detected: initiating config setup");
@@ -108,11 +257,11 @@ pub fn setup_file_struct() -> Result<()> {
                         .interact_text()?,
                 }
                 .parse::<u16>()?;
-                let url = format!("http://{}:{}", host, port);
-                if reqwest::blocking::get(&url).is_ok() {
+                let client = client::OllamaClient::new(&host, port, None)?;
+                if client.ping().is_ok() {
                     break;
                 }
-                let msg = format!("Ollama server not found at {}", url);
+                let msg = format!("Ollama server not found at {}", client.base_url());
                 fmt_print(&msg, ContentType::Error, color);
             }
             let model = get_input("Enter model", Some("llama3:latest".to_owned()), color)?;
@@ -125,6 +274,19 @@ pub fn setup_file_struct() -> Result<()> {
                 verbose,
                 color,
                 save,
+                chat: true,
+                options: ModelOptions::default(),
+                model_options: HashMap::new(),
+                cli_options: ModelOptions::default(),
+                system: None,
+                keep_alive: None,
+                embed_model: None,
+                auto_pull: false,
+                auto_pull_max_size: None,
+                format: None,
+                format_retries: 0,
+                kb: vec![],
+                kb_top_k: 0,
             };
             let mut file = File::create(conf_file)?;
             file.write_all(to_string(&conf)?.as_bytes())?;
@@ -208,7 +370,73 @@ pub fn get_config(matches: ArgMatches) -> Result<Config> {
     if matches.get_flag("save") {
         conf.save = true;
     }
+    if let Some(system) = matches.get_one::<String>("system") {
+        conf.system = Some(system.to_string());
+    }
+    if let Some(persona) = matches.get_one::<String>("persona") {
+        conf.system = Some(get_persona(persona)?);
+    }
+    if let Some(t) = matches.get_one::<String>("temperature") {
+        conf.cli_options.temperature = Some(t.parse::<f32>()?);
+    }
+    if let Some(n) = matches.get_one::<String>("num_ctx") {
+        conf.cli_options.num_ctx = Some(n.parse::<u32>()?);
+    }
+    if let Some(s) = matches.get_one::<String>("seed") {
+        conf.cli_options.seed = Some(s.parse::<i64>()?);
+    }
+    if let Some(p) = matches.get_one::<String>("top_p") {
+        conf.cli_options.top_p = Some(p.parse::<f32>()?);
+    }
+    if let Some(stop) = matches.get_many::<String>("stop") {
+        conf.cli_options.stop = Some(stop.cloned().collect());
+    }
+    if matches.get_one::<String>("format").is_some() {
+        conf.format = Some(serde_json::Value::String("json".to_string()));
+    }
+    if let Some(path) = matches.get_one::<String>("schema") {
+        let schema: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("Schema \"{}\" is not valid JSON -> {}", path, e))?;
+        JSONSchema::compile(&schema)
+            .map_err(|e| anyhow!("Schema \"{}\" is not a valid JSON schema -> {}", path, e))?;
+        conf.format = Some(schema);
+    }
+    if let Some(k) = matches.get_one::<String>("keep_alive") {
+        conf.keep_alive = Some(k.to_string());
+    }
+    if let Some(k) = &conf.keep_alive {
+        parse_keep_alive(k)?;
+    }
+    if let Some(dirs) = matches.get_many::<String>("kb") {
+        conf.kb = dirs.cloned().collect();
+    }
+    if let Some(k) = matches.get_one::<String>("top_k") {
+        conf.kb_top_k = k.parse::<usize>()?;
+    }
+    if matches.get_flag("pull_if_missing") {
+        conf.auto_pull = true;
+    }
+    if let Some(n) = matches.get_one::<String>("retries") {
+        conf.format_retries = n.parse::<u32>()?;
+    }
+    // Any explicit output mode is meant for pipes and files
+    if matches.value_source("output").is_some() {
+        conf.color = false;
+    }
     ensure!(conf.port < 65535, "Port out of bounds");
+    ensure!(
+        conf.auto_pull_max_size.is_none_or(|gb| gb >= 0.0),
+        "auto_pull_max_size must not be negative"
+    );
+    let opts = conf.effective_options();
+    ensure!(
+        opts.temperature.is_none_or(|t| t >= 0.0),
+        "Temperature must not be negative"
+    );
+    ensure!(
+        opts.top_p.is_none_or(|p| (0.0..=1.0).contains(&p)),
+        "top_p must be between 0 and 1"
+    );
     let msg = format!(
         "Ollama host {}:{} with model \"{}\"",
         &conf.host, &conf.port, &conf.model
@@ -217,7 +445,60 @@ pub fn get_config(matches: ArgMatches) -> Result<Config> {
     Ok(conf)
 }
 
+/// Sets the output mode from the "output" arg; call once, before printing
+pub fn set_output(matches: &ArgMatches) {
+    let output = match matches.get_one::<String>("output").map(String::as_str) {
+        Some("json") => Output::Json,
+        Some("ndjson") => Output::Ndjson,
+        _ => Output::Plain,
+    };
+    let _ = OUTPUT.set(output);
+}
+
+pub fn output() -> Output {
+    OUTPUT.get().copied().unwrap_or_default()
+}
+
+/// Prints one JSON value on its own line
+pub fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string(value) {
+        Ok(s) => print_line(&s),
+        Err(e) => fmt_error(&e.to_string(), ErrorCode::Other, false),
+    }
+}
+
+// Exits quietly once stdout is closed, e.g. by `rtwo -o ndjson -L | head -1`
+fn print_line(s: &str) {
+    let mut out = std::io::stdout().lock();
+    if let Err(e) = writeln!(out, "{}", s).and_then(|_| out.flush()) {
+        if e.kind() == std::io::ErrorKind::BrokenPipe {
+            std::process::exit(0);
+        }
+        eprintln!("Failed to write to stdout -> {}", e);
+    }
+}
+
+/// Prints an error, as {"error": {"code", "message"}} in the JSON output modes
+pub fn fmt_error(msg: &str, code: ErrorCode, color: bool) {
+    if output().is_json() {
+        let err = json!({"error": {"code": code.as_str(), "message": msg}});
+        eprintln!("{}", err);
+        return;
+    }
+    fmt_print(msg, ContentType::Error, color);
+}
+
 pub fn fmt_print(s: &str, content_type: ContentType, color: bool) {
+    if output().is_json() {
+        // Keep stdout for JSON
+        match content_type {
+            ContentType::Json => print_line(s),
+            ContentType::Error => fmt_error(s, ErrorCode::Other, false),
+            ContentType::AnswerBlock => eprint!("{}", s),
+            _ => eprintln!("{}", s),
+        }
+        return;
+    }
     if color {
         match content_type {
             ContentType::Error => eprintln!("{}", s.red()),
@@ -231,11 +512,22 @@ pub fn fmt_print(s: &str, content_type: ContentType, color: bool) {
                     .print()
                     .unwrap();
             }
+            ContentType::AnswerBlock => {
+                PrettyPrinter::new()
+                    .input_from_bytes(s.as_bytes())
+                    .grid(false)
+                    .language("markdown")
+                    .theme("DarkNeon")
+                    .print()
+                    .unwrap();
+            }
+            ContentType::Json => println!("{}", s),
             ContentType::Exit => println!("{}", s.green()),
         }
     } else {
         match content_type {
             ContentType::Error => eprintln!("{}", s),
+            ContentType::AnswerBlock => print!("{}", s),
             _ => println!("{}", s),
         }
     }
@@ -261,11 +553,67 @@ pub fn get_project_file(file: ProjFiles) -> Result<String> {
             ProjFiles::Data => {
                 return Ok(format!("{}/{}", proj.data_dir().to_str().unwrap(), DB_FILE));
             }
+            ProjFiles::Personas => {
+                return Ok(format!(
+                    "{}/{}",
+                    proj.config_dir().to_str().unwrap(),
+                    PERSONA_DIR
+                ));
+            }
+            ProjFiles::History => {
+                return Ok(format!(
+                    "{}/{}",
+                    proj.data_dir().to_str().unwrap(),
+                    HISTORY_FILE
+                ));
+            }
         }
     }
     Err(anyhow!("Could not get project file"))
 }
 
+/// System prompt from the persona file `name`, `name.md` or `name.txt`
+pub fn get_persona(name: &str) -> Result<String> {
+    let dir = get_project_file(ProjFiles::Personas)?;
+    let candidates = std::iter::once(format!("{}/{}", dir, name)).chain(
+        PERSONA_EXTS
+            .iter()
+            .map(|ext| format!("{}/{}.{}", dir, name, ext)),
+    );
+    for path in candidates {
+        if Path::new(&path).is_file() {
+            let system = read_file(&path)?.trim().to_string();
+            ensure!(!system.is_empty(), "Persona \"{}\" is empty", name);
+            return Ok(system);
+        }
+    }
+    Err(anyhow!(
+        "Persona \"{}\" not found in {} (available: {:?})",
+        name,
+        dir,
+        list_personas()?
+    ))
+}
+
+pub fn list_personas() -> Result<Vec<String>> {
+    let dir = get_project_file(ProjFiles::Personas)?;
+    if !Path::new(&dir).exists() {
+        return Ok(vec![]);
+    }
+    let mut personas: Vec<String> = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            personas.push(stem.to_string());
+        }
+    }
+    personas.sort();
+    Ok(personas)
+}
+
 fn read_file(path: &str) -> Result<String> {
     let mut s = String::new();
     let mut f = File::open(path)?;
@@ -273,9 +621,143 @@ fn read_file(path: &str) -> Result<String> {
     Ok(s)
 }
 
+/// A model name and its tag, if it has one. The tag is after the last ':' of
+/// the final path part ("host:5000/ns/model:tag")
+pub fn split_tag(model: &str) -> (&str, Option<&str>) {
+    match model.rsplit('/').next().is_some_and(|n| n.contains(':')) {
+        true => model
+            .rsplit_once(':')
+            .map_or((model, None), |(name, tag)| (name, Some(tag))),
+        false => (model, None),
+    }
+}
+
+// The other name of a model's latest tag: "llama3" for "llama3:latest" and back
+fn latest_alias(model: &str) -> Option<String> {
+    match split_tag(model) {
+        (name, Some("latest")) => Some(name.to_string()),
+        (_, Some(_)) => None,
+        (name, None) => Some(format!("{}:latest", name)),
+    }
+}
+
+// Seconds ("300", "-1") become numbers; durations ("10m", "1h30m") stay strings
+fn parse_keep_alive(keep_alive: &str) -> Result<serde_json::Value> {
+    if let Ok(secs) = keep_alive.parse::<i64>() {
+        return Ok(secs.into());
+    }
+    let units = ["ns", "us", "µs", "ms", "s", "m", "h"];
+    let mut rest = keep_alive.strip_prefix('-').unwrap_or(keep_alive);
+    ensure!(
+        !rest.is_empty(),
+        "Invalid keep alive \"{}\", use seconds or a duration such as 10m",
+        keep_alive
+    );
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.');
+        let unit = digits.and_then(|i| units.iter().find(|u| rest[i..].starts_with(*u)));
+        match (digits, unit) {
+            (Some(i), Some(unit)) if i > 0 => rest = &rest[i + unit.len()..],
+            _ => bail!(
+                "Invalid keep alive \"{}\", use seconds or a duration such as 10m",
+                keep_alive
+            ),
+        }
+    }
+    Ok(keep_alive.into())
+}
+
+fn default_true() -> bool {
+    true
+}
+
 fn validate_port_str(port_str: &str) -> Result<(), String> {
     if port_str.parse::<u16>().is_err() {
         return Err("Invalid port".to_owned());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(model: &str, tables: &str) -> Config {
+        let toml = format!(
+            "host = \"localhost\"\nport = 11434\nmodel = \"{}\"\nverbose = false\ncolor = false\nsave = false\n\n[options]\nnum_ctx = 8192\n\n{}",
+            model, tables
+        );
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn keep_alive_seconds_and_durations() {
+        for (given, sent) in [
+            ("0", json!(0)),
+            ("300", json!(300)),
+            ("-1", json!(-1)),
+            ("10m", json!("10m")),
+            ("1h30m", json!("1h30m")),
+            ("1.5h", json!("1.5h")),
+            ("250ms", json!("250ms")),
+            ("-5m", json!("-5m")),
+        ] {
+            assert_eq!(parse_keep_alive(given).unwrap(), sent, "{}", given);
+        }
+        for given in ["", "-", "--1", "m", "10x", "ten", "10m5", "1h 30m", " 5m"] {
+            let err = parse_keep_alive(given).unwrap_err().to_string();
+            assert!(err.starts_with("Invalid keep alive"), "{}", given);
+        }
+    }
+
+    #[test]
+    fn auto_pull_is_limited_by_default() {
+        let mut conf = config("llama3", "");
+        assert_eq!(conf.auto_pull_limit(), Some(10_000_000_000));
+        conf.auto_pull_max_size = Some(0.5);
+        assert_eq!(conf.auto_pull_limit(), Some(500_000_000));
+        conf.auto_pull_max_size = Some(0.0);
+        assert_eq!(conf.auto_pull_limit(), None);
+    }
+
+    #[test]
+    fn tag_is_in_the_last_path_part() {
+        assert_eq!(split_tag("llama3:70b"), ("llama3", Some("70b")));
+        assert_eq!(split_tag("llama3"), ("llama3", None));
+        assert_eq!(
+            split_tag("host:5000/ns/model"),
+            ("host:5000/ns/model", None)
+        );
+        assert_eq!(
+            split_tag("host:5000/ns/model:q4"),
+            ("host:5000/ns/model", Some("q4"))
+        );
+    }
+
+    #[test]
+    fn model_options_apply_to_the_latest_tag() {
+        let tables = "[model_options.\"llama3\"]\ntemperature = 0.1\n";
+        for model in ["llama3", "llama3:latest"] {
+            let opts = config(model, tables).effective_options();
+            assert_eq!(opts.temperature, Some(0.1), "{}", model);
+            assert_eq!(opts.num_ctx, Some(8192));
+        }
+        let opts = config("llama3:70b", tables).effective_options();
+        assert_eq!(opts.temperature, None);
+
+        let tables = "[model_options.\"llama3:latest\"]\ntemperature = 0.1\n\n[model_options.\"host:5000/llama3\"]\ntemperature = 0.3\n";
+        assert_eq!(
+            config("llama3", tables).effective_options().temperature,
+            Some(0.1)
+        );
+        let opts = config("host:5000/llama3:latest", tables).effective_options();
+        assert_eq!(opts.temperature, Some(0.3));
+
+        // The exact name wins over the alias, and args over both
+        let tables = "[model_options.\"llama3\"]\ntemperature = 0.1\n\n[model_options.\"llama3:latest\"]\ntemperature = 0.2\n";
+        let mut conf = config("llama3:latest", tables);
+        assert_eq!(conf.effective_options().temperature, Some(0.2));
+        conf.cli_options.temperature = Some(0.9);
+        assert_eq!(conf.effective_options().temperature, Some(0.9));
+    }
+}
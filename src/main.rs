@@ -1,9 +1,17 @@
+use std::fs;
+use std::io::{self, IsTerminal, Read};
 use std::process;
 
+use anyhow::{anyhow, bail, ensure, Result};
 use clap::{Arg, ArgMatches, Command};
+use lib::client::OllamaClient;
 
+mod attach;
 mod db;
+mod input;
+mod kb;
 mod ollama;
+mod repl;
 
 fn main() {
     // Setup Directories -> config, data
@@ -13,49 +21,139 @@ fn main() {
     }
     // Args
     let matches = get_matches();
+    lib::set_output(&matches);
     // Config
-    let conf = match lib::get_config(matches.clone()) {
+    let mut conf = match lib::get_config(matches.clone()) {
         Ok(c) => c,
         Err(e) => {
             let err_msg = format!("Failed to read config from file or args -> {}", e);
             lib::log(lib::LogLevel::Error, "main", &err_msg).unwrap();
-            eprintln!("{}", err_msg);
+            lib::fmt_error(&err_msg, lib::ErrorCode::Config, false);
             process::exit(1);
         }
     };
+    // Bring saved conversations up to the current schema
+    if let Err(e) = db::init() {
+        kill(
+            format!("Failed to update the database -> {}", e),
+            "db",
+            lib::ErrorCode::Database,
+            conf.color,
+        );
+    }
+    // Grep saved conversations; needs neither the server nor a model
+    if let Some(("history", history)) = matches.subcommand() {
+        if let Some(("grep", grep)) = history.subcommand() {
+            if let Err(e) = history_grep(grep, conf.color) {
+                let err_msg = format!("Failed to search history -> {}", e);
+                kill(err_msg, "db", lib::ErrorCode::History, conf.color);
+            }
+            process::exit(0);
+        }
+    }
+    let client = match OllamaClient::from_config(&conf) {
+        Ok(c) => c,
+        Err(e) => kill(
+            format!("Failed to create client -> {}", e),
+            "ollama",
+            lib::ErrorCode::Connection,
+            conf.color,
+        ),
+    };
     // Is ollama server in config/args up?
-    if ollama::valid_server(&conf).is_err() {
-        kill("Invalid server".to_owned(), "ollama", conf.color);
+    if ollama::valid_server(&client).is_err() {
+        kill(
+            "Invalid server".to_owned(),
+            "ollama",
+            lib::ErrorCode::Connection,
+            conf.color,
+        );
     }
     // Models on ollama host
-    let avail_models: Vec<String> = match ollama::get_models(&conf) {
+    let avail_models: Vec<String> = match ollama::get_models(&client, &conf) {
         Ok(m) => m,
         Err(e) => {
             let err_msg = format!(
                 "Failed to get available models from {}:{} -> {}",
                 conf.host, conf.port, e
             );
-            kill(err_msg, "ollama", conf.color);
+            kill(err_msg, "ollama", lib::ErrorCode::Connection, conf.color);
         }
     };
     if matches.get_flag("list_models") {
-        lib::fmt_print(
-            &format!("Available models: {:?}", avail_models),
-            lib::ContentType::Info,
-            conf.color,
-        );
-        lib::fmt_print(
-            &format!("Selected model: \"{}\"", &conf.model),
-            lib::ContentType::Info,
-            conf.color,
-        );
+        let sort = matches.get_one::<String>("sort").unwrap();
+        let family = matches.get_one::<String>("family").map(String::as_str);
+        let pattern = matches.get_one::<String>("filter").map(String::as_str);
+        if let Err(e) = ollama::list_models(sort, family, pattern, &client, &conf) {
+            let err_msg = format!(
+                "Failed to list models on {}:{} -> {}",
+                conf.host, conf.port, e
+            );
+            kill(err_msg, "ollama", lib::ErrorCode::Connection, conf.color);
+        }
+        process::exit(0);
+    }
+    // Model management subcommands ("history" restores into a session below)
+    if let Some((name, sub)) = matches.subcommand().filter(|(name, _)| *name != "history") {
+        let arg = |id: &str| sub.get_one::<String>(id).map_or("", String::as_str);
+        let (result, code) = match name {
+            "show" => {
+                let sections: Vec<&str> = ollama::SHOW_SECTIONS
+                    .iter()
+                    .copied()
+                    .filter(|s| sub.get_flag(s))
+                    .collect();
+                let result = ollama::show_model(arg("model"), &sections, &client, &conf);
+                (result, lib::ErrorCode::Show)
+            }
+            "copy" => (
+                ollama::copy_model(arg("source"), arg("destination"), &client, &conf),
+                lib::ErrorCode::Copy,
+            ),
+            "create" => (
+                ollama::create_model(arg("model"), arg("modelfile"), &client, &conf),
+                lib::ErrorCode::Create,
+            ),
+            "push" => (
+                ollama::push_model(arg("model"), &client, &conf),
+                lib::ErrorCode::Push,
+            ),
+            "load" => (
+                ollama::load_model(arg("model"), &avail_models, &client, &conf),
+                lib::ErrorCode::Load,
+            ),
+            "index" => (
+                index(sub, &avail_models, &client, &conf),
+                lib::ErrorCode::Index,
+            ),
+            "embed" => (
+                embed(sub, &avail_models, &client, &conf),
+                lib::ErrorCode::Embed,
+            ),
+            "unload" => (
+                ollama::unload_model(arg("model"), &avail_models, &client, &conf),
+                lib::ErrorCode::Unload,
+            ),
+            "ps" => (ollama::list_running(&client, &conf), lib::ErrorCode::Ps),
+            _ => (
+                Err(anyhow!("Unknown subcommand \"{}\"", name)),
+                lib::ErrorCode::Other,
+            ),
+        };
+        if let Err(e) = result {
+            let err_msg = format!("Failed to {} on {}:{} -> {}", name, conf.host, conf.port, e);
+            kill(err_msg, "ollama", code, conf.color);
+        }
         process::exit(0);
     }
     // Pull provided model to ollama host
     if matches.value_source("pull").is_some() {
         let model = matches.get_one::<String>("pull").unwrap().to_string();
-        match ollama::pull_model(model.clone(), avail_models, &conf) {
-            Ok(_) => {
+        match ollama::pull_model(model.clone(), avail_models, None, &client, &conf) {
+            Ok(status) => {
+                if let (Some(status), lib::Output::Json) = (status, lib::output()) {
+                    lib::print_json(&status);
+                }
                 let msg = format!("Model \"{}\" pulled to {}:{}", &model, conf.host, conf.port);
                 lib::log(lib::LogLevel::Info, "ollama", &msg).unwrap();
                 process::exit(0);
@@ -65,14 +163,14 @@ fn main() {
                     "Failed to pull model \"{}\" to {}:{} -> {}",
                     &model, conf.host, conf.port, e
                 );
-                kill(err_msg, "ollama", conf.color);
+                kill(err_msg, "ollama", lib::ErrorCode::Pull, conf.color);
             }
         }
     }
     // Delete provided model on ollama host
     if matches.value_source("del").is_some() {
         let model = matches.get_one::<String>("del").unwrap().to_string();
-        match ollama::del_model(model.clone(), avail_models, &conf) {
+        match ollama::del_model(model.clone(), avail_models, &client, &conf) {
             Ok(_) => {
                 let msg = format!(
                     "Model \"{}\" deleted from {}:{}",
@@ -87,95 +185,382 @@ fn main() {
                     "Failed to delete model \"{}\" from {}:{} -> {}",
                     &model, conf.host, conf.port, e
                 );
-                kill(err_msg, "ollama", conf.color);
+                kill(err_msg, "ollama", lib::ErrorCode::Delete, conf.color);
             }
         }
     }
-    // Ensure model in config is on ollama host
-    if !avail_models.iter().any(|m| m.contains(&conf.model)) {
-        let err_msg = format!(
-            "Model \"{}\" not available.\nAvailable models for {} include: {:?}",
-            &conf.model, &conf.host, avail_models
-        );
-        kill(err_msg, "ollama", conf.color);
-    }
+    // Resolve model in config to one on ollama host, or pick one when it is
+    // missing (interactive use only) or given as "?"
+    let interactive =
+        io::stdin().is_terminal() && io::stderr().is_terminal() && !lib::output().is_json();
+    let resolved = match conf.model.as_str() {
+        "?" => None,
+        name => match ollama::resolve_model(name, &avail_models) {
+            Ok(model) => Some(model),
+            Err(e) => {
+                let pulled = match conf.auto_pull {
+                    true => pull_missing(name, &avail_models, interactive, &client, &conf),
+                    false => Ok(None),
+                };
+                match pulled {
+                    Ok(Some(model)) => Some(model),
+                    Ok(None) if interactive => {
+                        lib::fmt_print(&e.to_string(), lib::ContentType::Error, conf.color);
+                        None
+                    }
+                    Ok(None) => {
+                        let err_msg = format!("No model on {}:{} -> {}", conf.host, conf.port, e);
+                        kill(err_msg, "ollama", lib::ErrorCode::ModelNotFound, conf.color);
+                    }
+                    Err(e) => {
+                        let err_msg = format!(
+                            "Failed to pull model \"{}\" to {}:{} -> {}",
+                            name, conf.host, conf.port, e
+                        );
+                        kill(err_msg, "ollama", lib::ErrorCode::Pull, conf.color);
+                    }
+                }
+            }
+        },
+    };
+    conf.model = match resolved {
+        Some(model) => model,
+        None => match ollama::pick_model(&avail_models, &conf.model, conf.color) {
+            Ok(model) => model,
+            Err(e) => kill(
+                format!("Failed to choose a model -> {}", e),
+                "ollama",
+                lib::ErrorCode::ModelNotFound,
+                conf.color,
+            ),
+        },
+    };
     // List saved conversations
-    if matches.get_flag("list") && db::list_conversations(conf.color).is_err() {
-        kill("Failed to list conversations".to_owned(), "db", conf.color);
+    if matches.get_flag("list") {
+        if let Err(e) = db::list_conversations(conf.color) {
+            kill(
+                format!("Failed to list conversations -> {}", e),
+                "db",
+                lib::ErrorCode::Database,
+                conf.color,
+            );
+        }
+        // A JSON list is the whole result
+        if lib::output().is_json() {
+            process::exit(0);
+        }
     }
     // Delete saved conversations
     if matches.get_flag("del_convo") && db::delete_conversations(conf.color).is_err() {
-        kill("Failed to delete conversation".to_owned(), "db", conf.color);
+        kill(
+            "Failed to delete conversation".to_owned(),
+            "db",
+            lib::ErrorCode::Database,
+            conf.color,
+        );
     }
-    let mut conversation: Vec<db::Chat> = vec![];
-    let mut context: Option<String> = None;
-    // Restore conversation
-    if matches.get_flag("restore") {
-        (context, conversation) = match db::restore_conversation(conf.color) {
-            Ok((ctx, convo)) => (ctx, convo),
+    let mut session = repl::Session::new(vec![], None, conf.system.clone());
+    // Restore conversation, chosen from the list or from history search matches
+    let restored = match matches.subcommand() {
+        Some(("history", history)) => {
+            match history_command(history, &avail_models, &client, &conf) {
+                Ok(Some(r)) => Some(Ok(r)),
+                Ok(None) => process::exit(0),
+                Err(e) => {
+                    let err_msg = format!("Failed to search history -> {}", e);
+                    kill(err_msg, "db", lib::ErrorCode::History, conf.color);
+                }
+            }
+        }
+        _ if matches.get_flag("restore") => Some(db::restore_conversation(conf.color)),
+        _ => None,
+    };
+    if let Some(restored) = restored {
+        session = match restored {
+            Ok(r) => {
+                // Saved system prompt unless one was given in args
+                let system = match matches.contains_id("system") || matches.contains_id("persona") {
+                    true => conf.system.clone(),
+                    false => r.system.clone().or(conf.system.clone()),
+                };
+                repl::Session::restored(r, system)
+            }
             Err(e) => {
                 let err_msg = format!("Failed to restore conversation -> {}", e);
-                kill(err_msg, "db", conf.color);
+                kill(err_msg, "db", lib::ErrorCode::Database, conf.color);
             }
         }
     }
-    // Main loop (Q&A)
-    loop {
-        let prompt: String = match lib::get_input("Ask R2", None, conf.color) {
-            Ok(s) => s,
-            Err(_) => {
-                kill("Failed to get user input".to_owned(), "main", conf.color);
+    // Attach files to the first prompt
+    if let Some(patterns) = matches.get_many::<String>("file") {
+        let patterns: Vec<String> = patterns.cloned().collect();
+        match attach::collect(&patterns) {
+            Ok((attachments, skipped)) => {
+                repl::print_skipped(&skipped, conf.color);
+                session.attachments = attachments;
             }
-        };
-        conversation.push(db::Chat {
-            role: "user".to_string(),
-            content: prompt.clone(),
-        });
-        context = match ollama::gen(prompt.replace('\"', "'"), context, &conf) {
-            Ok((ctx, resp)) => {
-                conversation.push(db::Chat {
-                    role: "assistant".to_string(),
-                    content: resp,
-                });
-                Some(ctx)
+            Err(e) => kill(
+                format!("Failed to attach files -> {}", e),
+                "attach",
+                lib::ErrorCode::Attach,
+                conf.color,
+            ),
+        }
+    }
+    // Images for the first prompt
+    if let Some(paths) = matches.get_many::<String>("image") {
+        let paths: Vec<String> = paths.cloned().collect();
+        match attach::check_images(&paths) {
+            Ok(paths) => session.images = paths,
+            Err(e) => kill(
+                format!("Failed to attach images -> {}", e),
+                "attach",
+                lib::ErrorCode::Attach,
+                conf.color,
+            ),
+        }
+    }
+    // Compose first prompt in $VISUAL/$EDITOR
+    if matches.get_flag("editor") {
+        match input::compose_in_editor("") {
+            Ok(Some(prompt)) => {
+                if let Err(e) = session.ask(prompt, &client, &conf) {
+                    let err_msg = format!(
+                        "Failed to generate response from {}:{} -> {}",
+                        conf.host, conf.port, e
+                    );
+                    kill(err_msg, "ollama", lib::ErrorCode::Generate, conf.color);
+                }
             }
-            Err(e) => {
+            Ok(None) => lib::fmt_print(
+                "Empty prompt, nothing sent",
+                lib::ContentType::Info,
+                conf.color,
+            ),
+            Err(e) => kill(
+                format!("Failed to compose prompt in editor -> {}", e),
+                "input",
+                lib::ErrorCode::Input,
+                conf.color,
+            ),
+        }
+    } else {
+        // One-shot (prompt from args and/or stdin)
+        let one_shot = match get_one_shot_prompt(&matches) {
+            Ok(p) => p,
+            Err(e) => kill(
+                format!("Failed to read prompt -> {}", e),
+                "main",
+                lib::ErrorCode::Input,
+                conf.color,
+            ),
+        };
+        if let Some(prompt) = one_shot {
+            if let Err(e) = session.ask(prompt, &client, &conf) {
                 let err_msg = format!(
                     "Failed to generate response from {}:{} -> {}",
                     conf.host, conf.port, e
                 );
-                kill(err_msg, "ollama", conf.color);
+                kill(err_msg, "ollama", lib::ErrorCode::Generate, conf.color);
             }
-        };
-        let ask_again = match lib::get_confirm("Ask another question?", None, conf.color) {
-            Ok(b) => b,
-            Err(_) => {
-                kill(
-                    "Failed to get user confirmation".to_owned(),
-                    "main",
-                    conf.color,
-                );
+            if conf.save {
+                if let Err(e) = session.save(&conf) {
+                    let err_msg = format!(
+                        "Failed to save conversation {}:{} -> {}",
+                        conf.host, conf.port, e
+                    );
+                    kill(err_msg, "db", lib::ErrorCode::Database, conf.color);
+                }
             }
-        };
-        if !ask_again {
-            break;
+            process::exit(0);
         }
     }
-    if conf.save || lib::get_confirm("Save conversation?", None, conf.color).unwrap() {
-        if let Err(e) = db::save_conversation(conversation, context, &conf) {
+    // Main loop (Q&A). The conversation is saved even if reading input fails
+    let result = repl::run(&mut session, &client, &mut conf);
+    if session.unsaved()
+        && (conf.save || lib::get_confirm("Save conversation?", None, conf.color).unwrap_or(false))
+    {
+        if let Err(e) = session.save(&conf) {
             let err_msg = format!(
                 "\nFailed to save conversation {}:{} -> {}",
                 conf.host, conf.port, e
             );
-            kill(err_msg, "db", conf.color);
+            kill(err_msg, "db", lib::ErrorCode::Database, conf.color);
         }
     }
+    if let Err(e) = result {
+        kill(
+            format!("Failed to get user input -> {}", e),
+            "main",
+            lib::ErrorCode::Input,
+            conf.color,
+        );
+    }
     lib::fmt_print("Goodbye", lib::ContentType::Exit, conf.color);
 }
 
-fn kill(msg: String, descriptor: &str, color: bool) -> ! {
+// Prompt from positional args, with piped stdin appended (e.g. `cat err.log | rtwo "explain"`)
+fn get_one_shot_prompt(matches: &ArgMatches) -> Result<Option<String>> {
+    let arg_prompt = matches
+        .get_many::<String>("prompt")
+        .map(|words| words.cloned().collect::<Vec<String>>().join(" "));
+    if io::stdin().is_terminal() {
+        return Ok(arg_prompt);
+    }
+    let mut piped = String::new();
+    io::stdin().read_to_string(&mut piped)?;
+    match (arg_prompt, piped.trim().is_empty()) {
+        (Some(p), true) => Ok(Some(p)),
+        (Some(p), false) => Ok(Some(format!("{}\n\n{}", p, piped.trim_end()))),
+        (None, false) => Ok(Some(piped.trim_end().to_string())),
+        (None, true) => bail!("No prompt provided on stdin"),
+    }
+}
+
+// Pulls a missing model for auto_pull, asking first in interactive use.
+// Returns the model's name once pulled, or None if the user declined.
+fn pull_missing(
+    name: &str,
+    avail_models: &[String],
+    interactive: bool,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Option<String>> {
+    if interactive {
+        let prompt = format!(
+            "Model \"{}\" is not on {}:{}. Pull it?",
+            name, conf.host, conf.port
+        );
+        if !lib::get_confirm(&prompt, Some(true), conf.color)? {
+            return Ok(None);
+        }
+    }
+    ollama::pull_model(
+        name.to_string(),
+        avail_models.to_vec(),
+        conf.auto_pull_limit(),
+        client,
+        conf,
+    )?;
+    let msg = format!("Model \"{}\" pulled to {}:{}", name, conf.host, conf.port);
+    lib::log(lib::LogLevel::Info, "ollama", &msg)?;
+    let avail_models = ollama::get_models(client, conf)?;
+    ollama::resolve_model(name, &avail_models).map(Some)
+}
+
+// Runs a history subcommand other than grep, returning the conversation chosen
+// to restore. search ranks conversations by meaning with --model or embed_model
+fn history_command(
+    history: &ArgMatches,
+    avail_models: &[String],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Option<db::Restored>> {
+    let sub = match history.subcommand() {
+        Some(("search", sub)) => sub,
+        _ => bail!("Unknown history command"),
+    };
+    let model = embed_model(sub, avail_models, conf)?;
+    let query = sub.get_one::<String>("query").unwrap();
+    let limit = sub.get_one::<String>("limit").unwrap().parse::<usize>()?;
+    ensure!(limit > 0, "Limit must be at least 1");
+    db::search_conversations(query, &model, limit, client, conf)
+}
+
+// Prints saved messages matching all TERMS, narrowed by the filter args
+fn history_grep(sub: &ArgMatches, color: bool) -> Result<()> {
+    let terms: Vec<String> = sub.get_many::<String>("terms").unwrap().cloned().collect();
+    let arg = |id: &str| sub.get_one::<String>(id).map(String::as_str);
+    let filter = db::HistoryFilter {
+        model: arg("model"),
+        host: arg("host"),
+        since: arg("since"),
+        until: arg("until"),
+    };
+    let limit = sub.get_one::<String>("limit").unwrap().parse::<usize>()?;
+    db::grep_conversations(&terms, &filter, limit, color)
+}
+
+// Embedding model from --model or embed_model, as named on the server
+fn embed_model(sub: &ArgMatches, avail_models: &[String], conf: &lib::Config) -> Result<String> {
+    match sub.get_one::<String>("model").or(conf.embed_model.as_ref()) {
+        Some(m) => ollama::resolve_model(m, avail_models),
+        None => bail!("No embedding model, set embed_model in the config file or use --model"),
+    }
+}
+
+// Indexes DIR with --model or embed_model, then reports what changed
+fn index(
+    sub: &ArgMatches,
+    avail_models: &[String],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let model = embed_model(sub, avail_models, conf)?;
+    let dir = sub.get_one::<String>("dir").unwrap();
+    let exclude: Vec<String> = sub
+        .get_many::<String>("exclude")
+        .map_or(vec![], |e| e.cloned().collect());
+    let stats = kb::index(dir, &exclude, &model, client, conf)?;
+    if lib::output().is_json() {
+        lib::print_json(&stats);
+        return Ok(());
+    }
+    repl::print_skipped(&stats.skipped, conf.color);
+    let msg = format!(
+        "Indexed {} files ({} chunks), {} unchanged, {} removed",
+        stats.indexed, stats.chunks, stats.unchanged, stats.removed
+    );
+    lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+    Ok(())
+}
+
+// Embeds each TEXT argument, then each line of every FILE ("-" for stdin, which
+// is also read when piped with no other input)
+fn embed(
+    sub: &ArgMatches,
+    avail_models: &[String],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let model = embed_model(sub, avail_models, conf)?;
+    let batch_size = sub
+        .get_one::<String>("batch_size")
+        .unwrap()
+        .parse::<usize>()?;
+    ensure!(batch_size > 0, "Batch size must be at least 1");
+    let mut texts: Vec<String> = sub
+        .get_many::<String>("text")
+        .map_or(vec![], |t| t.cloned().collect());
+    let mut files: Vec<String> = sub
+        .get_many::<String>("file")
+        .map_or(vec![], |f| f.cloned().collect());
+    if texts.is_empty() && files.is_empty() && !io::stdin().is_terminal() {
+        files.push("-".to_string());
+    }
+    for file in files {
+        let content = match file.as_str() {
+            "-" => {
+                let mut piped = String::new();
+                io::stdin().read_to_string(&mut piped)?;
+                piped
+            }
+            path => fs::read_to_string(path).map_err(|e| anyhow!("{} -> {}", path, e))?,
+        };
+        texts.extend(
+            content
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(str::to_string),
+        );
+    }
+    ensure!(!texts.is_empty(), "Nothing to embed");
+    ollama::embed(&texts, &model, batch_size, client, conf)
+}
+
+fn kill(msg: String, descriptor: &str, code: lib::ErrorCode, color: bool) -> ! {
     lib::log(lib::LogLevel::Error, descriptor, &msg).unwrap();
-    lib::fmt_print(&msg, lib::ContentType::Error, color);
+    lib::fmt_error(&msg, code, color);
     process::exit(1)
 }
 
@@ -185,6 +570,15 @@ fn get_matches() -> ArgMatches {
         .version("0.1.0")
         .author("ash")
         .arg_required_else_help(false)
+        .arg(
+            Arg::new("prompt")
+                .help("Prompt to answer once, then exit")
+                .long_help("Prompt to answer once, then exit (non-interactive). Input piped on stdin is appended to the prompt, or used as the prompt if none is given.\nExample: cat err.log | rtwo \"explain this error\"")
+                .value_name("PROMPT")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .num_args(1..),
+        )
         .arg(
             Arg::new("host")
                 .short('H')
@@ -212,18 +606,63 @@ fn get_matches() -> ArgMatches {
                 .short('m')
                 .long("model")
                 .help("Model name to query. eg: llama3")
-                .long_help("Model name to query. e.g.: mistral, llama3:70b, etc.\nNOTE: If model is not available on HOST, rtwo will not automatically download the model to the HOST. Use \"pull\" [-P, --pull] to download the model to the HOST.")
+                .long_help("Model name to query. e.g.: mistral, llama3:70b, etc. A name without a tag means its \":latest\" tag. \"?\" chooses from the models on HOST.\nNOTE: A model that is not on HOST is only downloaded when auto_pull is set in the config file or --pull-if-missing is given (up to auto_pull_max_size GB, 10 by default, asking first in an interactive terminal). Otherwise use \"pull\" [-P, --pull] to download the model to the HOST.")
                 .value_name("MODEL")
                 .required(false)
                 .action(clap::ArgAction::Set)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("editor")
+                .short('e')
+                .long("editor")
+                .conflicts_with("prompt")
+                .help("Compose the first prompt in $VISUAL/$EDITOR")
+                .long_help("Compose the first prompt in $VISUAL or $EDITOR (falls back to vi), then continue the session. Saving an empty buffer cancels the prompt.")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .short('f')
+                .long("file")
+                .help("Attach a file, directory or glob to the first prompt (repeatable)")
+                .long_help("Attach a file, directory or glob to the first prompt. Each file is sent as a fenced block labelled with its path and language ahead of the prompt. Directories are read recursively, skipping hidden entries and symlinked directories; binary files and files over 256 KiB are refused (or skipped inside directories and globs). Repeat for several. e.g.: -f src/main.rs -f \"docs/*.md\"")
+                .value_name("PATH")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("image")
+                .short('i')
+                .long("image")
+                .help("Send a PNG or JPEG image with the first prompt (repeatable)")
+                .long_help("Send a PNG or JPEG image with the first prompt, for vision models such as llava. Images up to 20 MiB; repeat for several. The image paths are saved with the conversation and read again when a restored conversation continues. e.g.: -i screenshot.png \"what does this error mean?\"")
+                .value_name("PATH")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Output mode for results: plain, json or ndjson")
+                .long_help("Output mode for results, for scripts wrapping rtwo. \"json\" prints answers (with the response metrics), model lists (-L), conversation lists (-l) and errors as JSON objects; \"ndjson\" prints one object per line, streaming answer tokens and list items. Only JSON goes to stdout in these modes; other messages go to stderr, and errors carry a stable \"code\". \"plain\" is text without color.")
+                .value_name("MODE")
+                .value_parser(["plain", "json", "ndjson"])
+                .global(true)
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
                 .long("verbose")
                 .help("Enable verbose output")
-                .long_help("Enable verbose output. Prints: model, tokens in prompt, tokens in response, and time taken after response is rendered to user.\nExample: \n\t* Model: llama3:70b\n\t* Tokens in prompt: 23\n\t* Tokens in response: 216\n\t* Time taken: 27.174")
+                .long_help("Enable verbose output. Prints: model, tokens in prompt, tokens in response, time taken and model load time after response is rendered to user.\nExample: \n\t* Model: llama3:70b\n\t* Tokens in prompt: 23\n\t* Tokens in response: 216\n\t* Time taken: 27.174\n\t* Load time: 4.210")
                 .required(false)
                 .action(clap::ArgAction::SetTrue),
         )
@@ -245,6 +684,141 @@ fn get_matches() -> ArgMatches {
                 .required(false)
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("system")
+                .long("system")
+                .conflicts_with("persona")
+                .help("System prompt for the session")
+                .long_help("System prompt for the session. Overrides \"system\" in the config file. e.g.: \"Answer in one paragraph.\"")
+                .value_name("TEXT")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("persona")
+                .long("persona")
+                .help("Use a named persona file as the system prompt")
+                .long_help("Use a named persona as the system prompt. Personas are prompt files in the \"personas\" directory next to the config file, e.g. personas/reviewer.md is selected with \"--persona reviewer\".")
+                .value_name("NAME")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("temperature")
+                .long("temperature")
+                .help("Sampling temperature (model option)")
+                .long_help("Sampling temperature sent in the request options. Higher is more creative, 0 is most deterministic. e.g.: 0.2\nOverrides [options] and [model_options.\"MODEL\"] in the config file.")
+                .value_name("TEMP")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("num_ctx")
+                .long("num-ctx")
+                .help("Context window size in tokens (model option)")
+                .long_help("Context window size in tokens sent in the request options. Raise for long documents. e.g.: 8192\nOverrides [options] and [model_options.\"MODEL\"] in the config file.")
+                .value_name("TOKENS")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .help("Random seed for reproducible output (model option)")
+                .long_help("Random seed sent in the request options. Use with a fixed temperature for reproducible output. e.g.: 42\nOverrides [options] and [model_options.\"MODEL\"] in the config file.")
+                .value_name("SEED")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("kb")
+                .long("kb")
+                .help("Answer from a directory indexed with \"rtwo index\"")
+                .long_help("Search a directory indexed with \"rtwo index\" for the chunks closest to each prompt, and send them with the prompt as numbered sources to cite. Repeat to search several directories. e.g.: --kb ~/src/rtwo")
+                .value_name("DIR")
+                .required(false)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("top_k")
+                .long("top-k")
+                .help("Sources sent with each prompt when using --kb")
+                .value_name("K")
+                .default_value("5")
+                .requires("kb")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("keep_alive")
+                .long("keep-alive")
+                .help("How long the model stays loaded after each request")
+                .long_help("How long the model stays in memory after each request (and after \"load\"): seconds, or a duration such as 10m or 1h. 0 unloads it straight away, -1 keeps it loaded. e.g.: 30m\nOverrides keep_alive in the config file.")
+                .value_name("DURATION")
+                .allow_hyphen_values(true)
+                .global(true)
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("top_p")
+                .long("top-p")
+                .help("Nucleus sampling threshold (model option)")
+                .long_help("Nucleus sampling threshold (0-1) sent in the request options. e.g.: 0.9\nOverrides [options] and [model_options.\"MODEL\"] in the config file.")
+                .value_name("P")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("stop")
+                .long("stop")
+                .help("Stop sequence (model option, repeatable)")
+                .long_help("Stop sequence sent in the request options; generation ends when the model emits it. Repeat for several sequences. e.g.: --stop \"</answer>\"\nOverrides [options] and [model_options.\"MODEL\"] in the config file.")
+                .value_name("SEQ")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("Ask for answers in a structured format (json)")
+                .long_help("Ask the model for answers in a structured format, passed to Ollama as \"format\". Answers are printed as plain JSON (no markdown rendering) once they parse, and rtwo exits non-zero if no valid answer arrives. e.g.: --format json")
+                .value_name("FORMAT")
+                .value_parser(["json"])
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("schema")
+                .long("schema")
+                .help("JSON schema answers must follow (implies --format json)")
+                .long_help("JSON schema file passed to Ollama as \"format\". Each answer is validated against the schema; invalid answers are re-prompted with the validation error (see --retries). Implies --format json. e.g.: --schema person.json")
+                .value_name("FILE")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("retries")
+                .long("retries")
+                .help("Times to re-prompt when a structured answer is invalid")
+                .long_help("Times to re-prompt when an answer is not valid JSON or does not match --schema. The prompt is sent again with the validation error. e.g.: 3")
+                .value_name("N")
+                .default_value("2")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
         .arg(
             Arg::new("list")
                 .short('l')
@@ -262,9 +836,43 @@ fn get_matches() -> ArgMatches {
                 .short('L')
                 .long("listmodels")
                 .help("List available models on ollama server (HOST:PORT)")
+                .long_help("List available models on ollama server (HOST:PORT) with their family, parameter count, quantization, size and age. The configured model is marked with \"*\". See --sort, --family and --filter.")
                 .required(false)
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("sort")
+                .long("sort")
+                .requires("list_models")
+                .help("Sort the model list (-L) by name, size or modified")
+                .long_help("Sort the model list (-L): \"name\" alphabetically, \"size\" largest first, \"modified\" newest first.")
+                .value_name("KEY")
+                .value_parser(["name", "size", "modified"])
+                .default_value("name")
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("family")
+                .long("family")
+                .requires("list_models")
+                .help("Only list models (-L) of a family. e.g.: llama")
+                .value_name("FAMILY")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .requires("list_models")
+                .help("Only list models (-L) whose name matches a pattern")
+                .long_help("Only list models (-L) whose name matches a pattern: a glob such as \"llama3*\", or text the name contains.")
+                .value_name("PATTERN")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .num_args(1),
+        )
         .arg(
             Arg::new("restore")
                 .short('r')
@@ -306,6 +914,14 @@ fn get_matches() -> ArgMatches {
                 .action(clap::ArgAction::Set)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("pull_if_missing")
+                .long("pull-if-missing")
+                .help("Pull the model first if it is not on the server")
+                .long_help("Pull the model first if it is not on HOST, then continue (same as auto_pull in the config file). Asks before pulling in an interactive terminal. The size of a model is only known once its download starts, so a model over auto_pull_max_size (10 GB by default) stops mid-download; the layers downloaded so far stay on HOST.")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("del")
                 .short('D')
@@ -321,5 +937,221 @@ fn get_matches() -> ArgMatches {
                 .action(clap::ArgAction::Set)
                 .num_args(1),
         )
+        .subcommand(
+            Command::new("show")
+                .about("Show a model's details, Modelfile, parameters, template and license")
+                .long_about("Show a model's details, Modelfile, parameters, template, system prompt and license. Prints every section unless some are selected.")
+                .arg(
+                    Arg::new("model")
+                        .help("Model to show. e.g.: llama3")
+                        .value_name("MODEL")
+                        .required(true),
+                )
+                .args(ollama::SHOW_SECTIONS.map(|section| {
+                    Arg::new(section)
+                        .long(section)
+                        .help(format!("Show the {}", section))
+                        .action(clap::ArgAction::SetTrue)
+                })),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy a model under a new name")
+                .arg(
+                    Arg::new("source")
+                        .help("Model to copy. e.g.: llama3")
+                        .value_name("SOURCE")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("destination")
+                        .help("New model name. e.g.: llama3-backup")
+                        .value_name("DESTINATION")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("create")
+                .about("Create a model from a Modelfile")
+                .arg(
+                    Arg::new("model")
+                        .help("Name of the new model. e.g.: reviewer")
+                        .value_name("MODEL")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("modelfile")
+                        .short('f')
+                        .long("file")
+                        .help("Path to the Modelfile")
+                        .value_name("FILE")
+                        .default_value("Modelfile"),
+                ),
+        )
+        .subcommand(
+            Command::new("push")
+                .about("Push a model to a registry")
+                .long_about("Push a model to a registry. The name must include the namespace, e.g.: myuser/reviewer:latest")
+                .arg(
+                    Arg::new("model")
+                        .help("Model to push. e.g.: myuser/reviewer")
+                        .value_name("MODEL")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("load")
+                .about("Load a model into memory ahead of use (kept for --keep-alive)")
+                .arg(
+                    Arg::new("model")
+                        .help("Model to load. e.g.: llama3")
+                        .value_name("MODEL")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("unload")
+                .about("Unload a model from memory")
+                .arg(
+                    Arg::new("model")
+                        .help("Model to unload. e.g.: llama3")
+                        .value_name("MODEL")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("index")
+                .about("Index a directory for --kb (only new and changed files are embedded)")
+                .arg(
+                    Arg::new("dir")
+                        .help("Directory to index. e.g.: ~/src/rtwo")
+                        .value_name("DIR")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .short('x')
+                        .long("exclude")
+                        .help("Skip files whose path in DIR matches a glob. e.g.: \"target/*\"")
+                        .value_name("PATTERN")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("model")
+                        .short('m')
+                        .long("model")
+                        .help("Embedding model. Overrides embed_model in the config file")
+                        .value_name("MODEL"),
+                ),
+        )
+        .subcommand(
+            Command::new("embed")
+                .about("Print embedding vectors for text, one JSON line per input")
+                .arg(
+                    Arg::new("text")
+                        .help("Text to embed, one vector per argument")
+                        .value_name("TEXT")
+                        .num_args(0..),
+                )
+                .arg(
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .help("Embed each line of a file (\"-\" for stdin)")
+                        .value_name("FILE")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("model")
+                        .short('m')
+                        .long("model")
+                        .help("Embedding model. Overrides embed_model in the config file")
+                        .value_name("MODEL"),
+                )
+                .arg(
+                    Arg::new("batch_size")
+                        .long("batch-size")
+                        .help("Texts sent in each request")
+                        .value_name("N")
+                        .default_value("64"),
+                ),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("Search saved conversations")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("search")
+                        .about("Find saved conversations by meaning and restore one")
+                        .arg(
+                            Arg::new("query")
+                                .help("What the conversation was about. e.g.: \"sqlite migrations\"")
+                                .value_name("QUERY")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("model")
+                                .short('m')
+                                .long("model")
+                                .help("Embedding model. Overrides embed_model in the config file")
+                                .value_name("MODEL"),
+                        )
+                        .arg(
+                            Arg::new("limit")
+                                .short('n')
+                                .long("limit")
+                                .help("Conversations listed")
+                                .value_name("N")
+                                .default_value("10"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("grep")
+                        .about("Print saved messages containing all TERMS, with the terms highlighted")
+                        .arg(
+                            Arg::new("terms")
+                                .help("Words or quoted phrases to find; end one with * to match a prefix. e.g.: sqlite \"schema version\"")
+                                .value_name("TERMS")
+                                .num_args(1..)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("model")
+                                .short('m')
+                                .long("model")
+                                .help("Only conversations with this model. e.g.: llama3")
+                                .value_name("MODEL"),
+                        )
+                        .arg(
+                            Arg::new("host")
+                                .long("host")
+                                .help("Only conversations on this host, with or without the port. e.g.: localhost")
+                                .value_name("HOST"),
+                        )
+                        .arg(
+                            Arg::new("since")
+                                .long("since")
+                                .help("Only conversations saved on or after this date (YYYY-MM-DD or RFC 3339)")
+                                .value_name("DATE"),
+                        )
+                        .arg(
+                            Arg::new("until")
+                                .long("until")
+                                .help("Only conversations saved before the end of this date (YYYY-MM-DD or RFC 3339)")
+                                .value_name("DATE"),
+                        )
+                        .arg(
+                            Arg::new("limit")
+                                .short('n')
+                                .long("limit")
+                                .help("Messages printed")
+                                .value_name("N")
+                                .default_value("20"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("ps").about("List models loaded in memory and their GPU (VRAM) use"),
+        )
         .get_matches()
 }
@@ -1,192 +1,662 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::StatusCode;
-use serde_derive::Deserialize;
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use console::{measure_text_width, Term};
+use dialoguer::{theme::ColorfulTheme, Select};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use jsonschema::JSONSchema;
+use lib::client::{
+    ChatMessage, ChatRequest, EmbedRequest, GenerateRequest, GenerateResponse, Model, ModelOptions,
+    OllamaClient, PullResponse,
+};
+use serde_derive::Serialize;
+
+use crate::{attach, db};
 
 const SPINNER: &[&str] = &["▹▹▹▹▹", "▸▹▹▹▹", "▹▸▹▹▹", "▹▹▸▹▹", "▹▹▹▸▹", "▹▹▹▹▸", "✔"];
 const SPINNER_ERR: &[&str] = &["✘"];
+// Texts embedded per request by `embed_texts`
+const EMBED_BATCH: usize = 32;
 
-#[derive(Deserialize)]
-pub struct GenerateResponse {
-    pub error: Option<String>,
-    pub model: Option<String>,
-    pub created_at: Option<String>,
-    pub response: Option<String>,
-    pub done: Option<bool>,
-    pub context: Option<Vec<i64>>,
-    pub total_duration: Option<u64>,
-    pub load_duration: Option<u64>,
-    pub prompt_eval_count: Option<u64>,
-    pub prompt_eval_duration: Option<u64>,
-    pub eval_count: Option<u64>,
-    pub eval_duration: Option<u64>,
-}
-
-#[derive(Deserialize)]
-pub struct ModelResponse {
-    pub models: Vec<Model>,
-}
-
-#[derive(Deserialize)]
-pub struct Model {
-    pub name: String,
-    pub modified_at: String,
-    pub size: i64,
-    pub digest: String,
-    pub details: Details,
-}
-
-#[derive(Deserialize)]
-pub struct Details {
-    pub format: String,
-    pub family: String,
-    pub families: Option<Vec<String>>,
-    pub parameter_size: String,
-    pub quantization_level: String,
-}
-
-#[derive(Deserialize)]
-pub struct PullResponse {
-    pub error: Option<String>,
-    pub status: Option<String>,
-}
-
-pub fn valid_server(conf: &lib::Config) -> Result<()> {
-    let full_url = format!("http://{}:{}", conf.host, conf.port);
-    let _ = reqwest::blocking::get(full_url)?;
-    Ok(())
+// Set while a pull is streaming, so Ctrl-C stops it cleanly instead of exiting
+static PULLING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static HANDLER: Once = Once::new();
+
+pub fn valid_server(client: &OllamaClient) -> Result<()> {
+    client.ping()
 }
 
-pub fn del_model(name: String, avail_models: Vec<String>, conf: &lib::Config) -> Result<()> {
+pub fn del_model(
+    name: String,
+    avail_models: Vec<String>,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
     let del_msg = format!("Attempting to delete model \"{}\"", &name);
     lib::fmt_print(&del_msg, lib::ContentType::Exit, conf.color);
-    if !avail_models.contains(&name) {
-        bail!("Model not found");
-    }
+    let name = resolve_model(&name, &avail_models)?;
     let msg = format!(
         "Attempting to delete model \"{}\" from {}:{}",
         &name, conf.host, conf.port
     );
     lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
-    let full_url = format!("http://{}:{}/api/delete", conf.host, conf.port);
-    let mut payload: HashMap<String, String> = HashMap::new();
-    payload.insert("name".to_string(), name.clone());
-    let body = get_postdata(payload);
-    let client = reqwest::blocking::Client::new();
-    let resp = client.delete(full_url).body(body).send()?;
-    if resp.status() != StatusCode::OK {
-        bail!("Server error deleting model");
-    }
-    Ok(())
+    client
+        .delete(&name)
+        .map_err(|e| anyhow!("Server error deleting model -> {}", e))
 }
 
-pub fn pull_model(name: String, avail_models: Vec<String>, conf: &lib::Config) -> Result<()> {
+/// Pulls a model with a progress bar per layer. Stops once the layers seen
+/// add up to more than `max_size` bytes. Returns the final status, or `None`
+/// if the model was already on the server.
+pub fn pull_model(
+    name: String,
+    avail_models: Vec<String>,
+    max_size: Option<u64>,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Option<PullResponse>> {
     let msg = format!(
         "Attempting to pull model \"{}\" to {}:{}",
         &name, conf.host, conf.port
     );
     lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
-    if avail_models.contains(&name) {
+    if resolve_model(&name, &avail_models).is_ok() {
         lib::fmt_print(
             "Model already exists on server",
             lib::ContentType::Exit,
             conf.color,
         );
-        return Ok(());
+        return Ok(None);
     }
-    let full_url = format!("http://{}:{}/api/pull", conf.host, conf.port);
-    let mut payload: HashMap<String, String> = HashMap::new();
-    payload.insert("name".to_string(), name.clone());
-    payload.insert("stream".to_string(), "false".to_string());
-    let body = get_postdata(payload);
-    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
-    let pb = start_spinner(conf.color);
+    watch_interrupt();
+    let mp = MultiProgress::new();
+    let pb = mp.add(start_spinner(conf.color));
     pb.set_message(format!("Downloading \"{}\"", &name));
-    let resp = client.post(full_url).body(body).send()?;
-    let ollama_resp: PullResponse = serde_json::from_str(&resp.text()?)?;
-    if let Some(err) = ollama_resp.error {
-        finish_spinner_error(pb, conf.color);
-        bail!(err);
-    }
-    if let Some(status) = ollama_resp.status {
-        if status == "success" {
+    let mut layers: HashMap<String, ProgressBar> = HashMap::new();
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    PULLING.store(true, Ordering::SeqCst);
+    let result = client.pull_stream(&name, |status| {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            bail!("Interrupted, pull the model again to resume the download");
+        }
+        if lib::output() == lib::Output::Ndjson {
+            lib::print_json(status);
+        }
+        if let (Some(digest), Some(total)) = (&status.digest, status.total) {
+            let bar = layers
+                .entry(digest.clone())
+                .or_insert_with(|| mp.insert_before(&pb, layer_bar(digest, total, conf.color)));
+            bar.set_position(status.completed.unwrap_or(0));
+            if bar.position() >= total && !bar.is_finished() {
+                bar.finish();
+            }
+            if let Some(max) = max_size {
+                let size: u64 = layers.values().filter_map(ProgressBar::length).sum();
+                if size > max {
+                    bail!(
+                        "Model is at least {}, over the {} limit. The layers downloaded so far stay on the server",
+                        human_size(size as i64),
+                        human_size(max as i64)
+                    );
+                }
+            }
+        }
+        pb.set_message(status.status.clone().unwrap_or_default());
+        Ok(())
+    });
+    PULLING.store(false, Ordering::SeqCst);
+    layers
+        .values()
+        .filter(|bar| !bar.is_finished())
+        .for_each(ProgressBar::abandon);
+    // Finished rather than cleared, so the layer bars above stay on screen
+    match result {
+        Ok(status) => {
             pb.finish_with_message("Done");
+            Ok(Some(status))
+        }
+        Err(e) => {
+            finish_spinner_error(pb, conf.color);
+            Err(e)
+        }
+    }
+}
+
+// Output of `embed`, with the input first so lines read naturally
+#[derive(Serialize)]
+struct Embedding<'a> {
+    input: &'a str,
+    embedding: Vec<f32>,
+}
+
+// Ctrl-C during a pull ends the stream at the next progress update, leaving
+// the partial layers on the server; a second Ctrl-C, or one at any other
+// time, exits straight away
+fn watch_interrupt() {
+    HANDLER.call_once(|| {
+        let handler = ctrlc::set_handler(|| {
+            if !PULLING.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        });
+        if let Err(e) = handler {
+            let msg = format!("Failed to set Ctrl-C handler -> {}", e);
+            let _ = lib::log(lib::LogLevel::Error, "ollama", &msg);
+        }
+    });
+}
+
+// Download bar for one layer of a pull, with throughput and time remaining
+fn layer_bar(digest: &str, total: u64, color: bool) -> ProgressBar {
+    let template = match color {
+        true => "{msg:.green} [{bar:30.blue}] {decimal_bytes:>10}/{decimal_total_bytes:<10} {decimal_bytes_per_sec:>12} {eta:>4}",
+        false => "{msg} [{bar:30}] {decimal_bytes:>10}/{decimal_total_bytes:<10} {decimal_bytes_per_sec:>12} {eta:>4}",
+    };
+    let bar = ProgressBar::new(total);
+    bar.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    let id = digest.trim_start_matches("sha256:");
+    bar.set_message(id.chars().take(12).collect::<String>());
+    bar
+}
+
+/// Sections printed by `show_model`
+pub const SHOW_SECTIONS: [&str; 5] = ["modelfile", "parameters", "template", "system", "license"];
+
+/// Prints a model's details and the chosen sections (all when empty)
+pub fn show_model(
+    name: &str,
+    sections: &[&str],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let msg = format!(
+        "Attempting to show model \"{}\" on {}:{}",
+        name, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let info = client.show(name)?;
+    if lib::output().is_json() {
+        lib::print_json(&info);
+        return Ok(());
+    }
+    if let Some(d) = &info.details {
+        let details = format!(
+            "Family: {}\nParameters: {}\nQuantization: {}\nFormat: {}",
+            d.family, d.parameter_size, d.quantization_level, d.format
+        );
+        lib::fmt_print(
+            &format!("Model \"{}\"", name),
+            lib::ContentType::Exit,
+            conf.color,
+        );
+        lib::fmt_print(&details, lib::ContentType::Info, conf.color);
+    }
+    for section in &SHOW_SECTIONS {
+        if !sections.is_empty() && !sections.contains(section) {
+            continue;
+        }
+        let text = match *section {
+            "modelfile" => &info.modelfile,
+            "parameters" => &info.parameters,
+            "template" => &info.template,
+            "system" => &info.system,
+            _ => &info.license,
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        let heading = format!("\n{}{}", section[..1].to_uppercase(), &section[1..]);
+        lib::fmt_print(&heading, lib::ContentType::Exit, conf.color);
+        println!("{}", text.trim_end());
+    }
+    Ok(())
+}
+
+pub fn copy_model(
+    source: &str,
+    destination: &str,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let msg = format!(
+        "Attempting to copy model \"{}\" to \"{}\" on {}:{}",
+        source, destination, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    client.copy(source, destination)?;
+    match lib::output().is_json() {
+        true => lib::print_json(&serde_json::json!({"status": "success"})),
+        false => {
+            let msg = format!("Copied \"{}\" to \"{}\"", source, destination);
+            lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+        }
+    }
+    Ok(())
+}
+
+/// Loads a model into memory with an empty prompt, kept for `keep_alive`
+/// (or the server default)
+pub fn load_model(
+    name: &str,
+    avail_models: &[String],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let name = &resolve_model(name, avail_models)?;
+    let msg = format!(
+        "Attempting to load model \"{}\" on {}:{}",
+        name, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let pb = start_spinner(conf.color);
+    pb.set_message(format!("Loading \"{}\"", name));
+    let req = GenerateRequest {
+        model: name.to_string(),
+        keep_alive: conf.keep_alive(),
+        ..Default::default()
+    };
+    let resp = finish_request(client.generate(req), pb, conf)?;
+    match lib::output().is_json() {
+        true => lib::print_json(&resp),
+        false => {
+            let secs = resp.load_duration.unwrap_or(0) as f64 / 1000000000.0;
+            let msg = format!("Loaded \"{}\" in {:.3}s", name, secs);
+            lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+        }
+    }
+    Ok(())
+}
+
+/// Unloads a model from memory (an empty prompt with a keep alive of 0)
+pub fn unload_model(
+    name: &str,
+    avail_models: &[String],
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let name = &resolve_model(name, avail_models)?;
+    let msg = format!(
+        "Attempting to unload model \"{}\" on {}:{}",
+        name, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let req = GenerateRequest {
+        model: name.to_string(),
+        keep_alive: Some(0.into()),
+        ..Default::default()
+    };
+    let resp = client.generate(req)?;
+    match lib::output().is_json() {
+        true => lib::print_json(&resp),
+        false => {
+            let msg = format!("Unloaded \"{}\"", name);
+            lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the embedding of each text, sending `batch_size` texts per request:
+/// a {"input", "embedding"} line per text, or one object with -o json
+pub fn embed(
+    texts: &[String],
+    model: &str,
+    batch_size: usize,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let msg = format!(
+        "Attempting to embed {} texts with \"{}\" on {}:{}",
+        texts.len(),
+        model,
+        conf.host,
+        conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let pb = start_spinner(conf.color);
+    pb.set_message(format!("Embedding 0/{}", texts.len()));
+    let mut embeddings: Vec<Embedding> = vec![];
+    let mut done = 0;
+    let result = embed_batches(texts, model, batch_size, client, conf, |batch, vectors| {
+        for (input, embedding) in batch.iter().zip(vectors) {
+            let item = Embedding { input, embedding };
+            match lib::output() {
+                lib::Output::Json => embeddings.push(item),
+                _ => pb.suspend(|| lib::print_json(&item)),
+            }
+        }
+        done += batch.len();
+        pb.set_message(format!("Embedding {}/{}", done, texts.len()));
+        Ok(())
+    });
+    finish_request(result, pb, conf)?;
+    if lib::output() == lib::Output::Json {
+        lib::print_json(&serde_json::json!({ "model": model, "embeddings": embeddings }));
+    }
+    Ok(())
+}
+
+/// Vectors for `texts` in order, embedded in batches behind a progress bar
+pub fn embed_texts(
+    texts: &[String],
+    model: &str,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<Vec<Vec<f32>>> {
+    let mut vectors: Vec<Vec<f32>> = vec![];
+    if texts.is_empty() {
+        return Ok(vectors);
+    }
+    let template = match conf.color {
+        true => "{msg:.green} [{bar:30.blue}] {pos}/{len} {eta:>4}",
+        false => "{msg} [{bar:30}] {pos}/{len} {eta:>4}",
+    };
+    let pb = ProgressBar::new(texts.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_message(format!("Embedding with \"{}\"", model));
+    let result = embed_batches(texts, model, EMBED_BATCH, client, conf, |batch, v| {
+        vectors.extend(v);
+        pb.inc(batch.len() as u64);
+        Ok(())
+    });
+    pb.finish_and_clear();
+    result?;
+    Ok(vectors)
+}
+
+/// Embeds `texts` with `model`, `batch_size` texts per request, calling
+/// `on_batch` with each batch and its vectors
+pub fn embed_batches<'t, F>(
+    texts: &'t [String],
+    model: &str,
+    batch_size: usize,
+    client: &OllamaClient,
+    conf: &lib::Config,
+    mut on_batch: F,
+) -> Result<()>
+where
+    F: FnMut(&'t [String], Vec<Vec<f32>>) -> Result<()>,
+{
+    for batch in texts.chunks(batch_size) {
+        let req = EmbedRequest {
+            model: model.to_string(),
+            input: batch.to_vec(),
+            keep_alive: conf.keep_alive(),
+            ..Default::default()
+        };
+        on_batch(batch, client.embed(req)?.embeddings)?;
+    }
+    Ok(())
+}
+
+/// Creates `name` from the Modelfile at `path`
+pub fn create_model(
+    name: &str,
+    path: &str,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let modelfile =
+        fs::read_to_string(path).map_err(|e| anyhow!("Cannot read \"{}\" -> {}", path, e))?;
+    let msg = format!(
+        "Attempting to create model \"{}\" from {} on {}:{}",
+        name, path, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let pb = start_spinner(conf.color);
+    pb.set_message(format!("Creating \"{}\"", name));
+    let result = client.create_stream(name, &modelfile, |status| print_status(status, &pb));
+    finish_status(result, pb, conf)
+}
+
+pub fn push_model(name: &str, client: &OllamaClient, conf: &lib::Config) -> Result<()> {
+    let msg = format!(
+        "Attempting to push model \"{}\" from {}:{}",
+        name, conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let pb = start_spinner(conf.color);
+    pb.set_message(format!("Pushing \"{}\"", name));
+    let result = client.push_stream(name, |status| print_status(status, &pb));
+    finish_status(result, pb, conf)
+}
+
+/// Prints the models loaded in memory, their size and how much is on the GPU
+pub fn list_running(client: &OllamaClient, conf: &lib::Config) -> Result<()> {
+    let models = client.ps()?;
+    match lib::output() {
+        lib::Output::Json => {
+            lib::print_json(&serde_json::json!({ "models": models }));
+            return Ok(());
+        }
+        lib::Output::Ndjson => {
+            models.iter().for_each(lib::print_json);
             return Ok(());
         }
-        pb.finish_with_message("Error");
+        lib::Output::Plain => (),
+    }
+    if models.is_empty() {
+        lib::fmt_print("No models loaded", lib::ContentType::Info, conf.color);
+        return Ok(());
     }
-    Err(anyhow!("Error downloading model"))
+    let rows: Vec<[String; 5]> = models
+        .iter()
+        .map(|m| {
+            [
+                m.name.clone(),
+                m.digest.chars().take(12).collect(),
+                human_size(m.size),
+                processor(m.size, m.size_vram.unwrap_or(0)),
+                m.expires_at.as_deref().map_or("-".to_string(), until),
+            ]
+        })
+        .collect();
+    print_table(
+        &["NAME", "ID", "SIZE", "PROCESSOR", "UNTIL"],
+        &rows,
+        conf.color,
+    );
+    Ok(())
 }
 
-pub fn gen(prompt: String, ctx: Option<String>, conf: &lib::Config) -> Result<(String, String)> {
+pub fn gen(
+    prompt: String,
+    images: &[String],
+    ctx: Option<Vec<i64>>,
+    system: Option<&str>,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<GenerateResponse> {
     let msg = format!(
         "Attempting to generate response from {}:{}",
         conf.host, conf.port
     );
     lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
-    let full_url = format!("http://{}:{}/api/generate", conf.host, conf.port);
-    let mut payload: HashMap<String, String> = HashMap::new();
-    payload.insert("model".to_string(), conf.model.to_string());
-    payload.insert("prompt".to_string(), prompt);
-    payload.insert("stream".to_string(), "false".to_string());
-    if let Some(context) = ctx {
-        payload.insert("context".to_string(), context);
-    }
-    let body = get_postdata(payload);
-    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+    let images = attach::encode_images(images)?;
+    let payload = GenerateRequest {
+        model: conf.model.to_string(),
+        prompt,
+        stream: true,
+        context: ctx,
+        system: system.map(str::to_string),
+        images: Some(images).filter(|i| !i.is_empty()),
+        format: conf.format.clone(),
+        options: request_options(conf),
+        keep_alive: conf.keep_alive(),
+    };
+    let ollama_resp = answer(conf, |on_token| match on_token {
+        None => client.generate(payload),
+        Some(on_token) => client.generate_stream(payload, |chunk| {
+            on_token(chunk.response.as_deref().unwrap_or(""))
+        }),
+    })?;
+    if ollama_resp.context.is_none() {
+        bail!("Context not found");
+    }
+    Ok(ollama_resp)
+}
+
+pub fn chat(
+    conversation: &[db::Chat],
+    system: Option<&str>,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<GenerateResponse> {
+    let msg = format!(
+        "Attempting to generate chat response from {}:{}",
+        conf.host, conf.port
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let mut messages: Vec<ChatMessage> = system
+        .map(|s| ChatMessage {
+            role: "system".to_string(),
+            content: s.to_string(),
+            ..Default::default()
+        })
+        .into_iter()
+        .collect();
+    let last = conversation.len().saturating_sub(1);
+    for (i, c) in conversation.iter().enumerate() {
+        let images = match attach::encode_images(&c.images) {
+            Ok(images) => images,
+            // Images from earlier (e.g. restored) turns may have moved since
+            Err(e) if i < last => {
+                lib::log(lib::LogLevel::Error, "ollama", &e.to_string())?;
+                vec![]
+            }
+            Err(e) => return Err(e),
+        };
+        messages.push(ChatMessage {
+            role: c.role.clone(),
+            content: c.request_content(),
+            images,
+        });
+    }
+    let payload = ChatRequest {
+        model: conf.model.to_string(),
+        messages,
+        stream: true,
+        format: conf.format.clone(),
+        options: request_options(conf),
+        keep_alive: conf.keep_alive(),
+    };
+    answer(conf, |on_token| match on_token {
+        None => client.chat(payload),
+        Some(on_token) => client.chat_stream(payload, |chunk| {
+            on_token(chunk.message.as_ref().map_or("", |m| m.content.as_str()))
+        }),
+    })
+}
+
+// Sends a request behind a spinner. `send` gets a callback that streams each
+// token of the answer to the terminal, or `None` for a structured answer,
+// which the caller checks, prints and summarizes
+fn answer<R: Into<GenerateResponse>>(
+    conf: &lib::Config,
+    send: impl FnOnce(Option<&mut dyn FnMut(&str) -> Result<()>>) -> Result<R>,
+) -> Result<GenerateResponse> {
     let pb = start_spinner(conf.color);
     pb.set_message("Processing");
-    let resp = client.post(full_url).body(body).send()?;
-    let ollama_resp: GenerateResponse = serde_json::from_str(&resp.text()?)?;
-    if let Some(err) = ollama_resp.error {
-        finish_spinner_error(pb, conf.color);
-        bail!(err);
-    }
-    pb.finish_with_message("Done");
-    let response = match ollama_resp.response {
-        Some(s) => {
-            lib::fmt_print(&s, lib::ContentType::Answer, conf.color);
-            s
-        }
-        None => bail!("Response not found"),
-    };
-    let context = match ollama_resp.context {
-        Some(s) => format!("{:?}", s),
-        None => bail!("Context not found"),
+    let resp = match conf.format.is_some() {
+        true => finish_request(send(None), pb, conf)?,
+        false => {
+            let mut printer = StreamPrinter::new(conf.color);
+            let result = send(Some(&mut |token| printer.push_token(token, &pb)));
+            finish_stream(result, printer, pb, conf)?
+        }
     };
-    if conf.verbose {
-        let model = ollama_resp.model.unwrap_or("Unknown".to_string());
-        let prompt_eval_count = ollama_resp.prompt_eval_count.unwrap_or(0);
-        let eval_count = ollama_resp.eval_count.unwrap_or(0);
-        let total_duration: f64 = ollama_resp.total_duration.unwrap_or(0) as f64 / 1000000000.0;
-        let msg = format!(
-            "Response generated from {}:{} -> [\"{}\",{},{},{}]",
-            conf.host, conf.port, model, prompt_eval_count, eval_count, total_duration
-        );
-        lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
-        lib::fmt_print("\nDone", lib::ContentType::Info, conf.color);
-        let info = format!(
-            "* Model: {}\n* Tokens in prompt: {}\n* Tokens in response: {}\n* Time taken: {:.3}s",
-            model, prompt_eval_count, eval_count, total_duration
-        );
-        lib::fmt_print(&info, lib::ContentType::Info, conf.color);
+    let resp: GenerateResponse = resp.into();
+    if resp.response.as_deref().is_none_or(str::is_empty) {
+        bail!("Response not found");
+    }
+    if conf.verbose && conf.format.is_none() {
+        print_summary(&resp, conf)?;
+    }
+    Ok(resp)
+}
+
+/// Server model that `name` refers to: the exact name, or its `:latest` tag
+/// when no tag is given. Other tags are never chosen implicitly, so "llama3"
+/// with only "llama3:70b" on the server is an error naming that tag.
+pub fn resolve_model(name: &str, avail_models: &[String]) -> Result<String> {
+    if avail_models.iter().any(|m| m == name) {
+        return Ok(name.to_string());
     }
-    Ok((context, response))
+    if lib::split_tag(name).1.is_none() {
+        let latest = format!("{}:latest", name);
+        if avail_models.contains(&latest) {
+            return Ok(latest);
+        }
+    }
+    let tags: Vec<&str> = avail_models
+        .iter()
+        .filter(|m| lib::split_tag(m).0 == name)
+        .map(String::as_str)
+        .collect();
+    match tags[..] {
+        [] => bail!(
+            "Model \"{}\" not available.\nAvailable models: {}",
+            name,
+            avail_models.join(", ")
+        ),
+        [tag] => bail!(
+            "Model \"{}\" not available, did you mean \"{}\"?",
+            name,
+            tag
+        ),
+        _ => bail!(
+            "Model \"{}\" is ambiguous, name one of: {}",
+            name,
+            tags.join(", ")
+        ),
+    }
+}
+
+/// Lets the user choose one of the server's models, starting on `current`
+pub fn pick_model(avail_models: &[String], current: &str, color: bool) -> Result<String> {
+    if avail_models.is_empty() {
+        bail!("No models on the server, pull one with -P");
+    }
+    let default = avail_models.iter().position(|m| m == current).unwrap_or(0);
+    let idx = match color {
+        true => Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Choose a model")
+            .items(avail_models)
+            .default(default)
+            .interact()?,
+        false => Select::new()
+            .with_prompt("Choose a model")
+            .items(avail_models)
+            .default(default)
+            .interact()?,
+    };
+    Ok(avail_models[idx].clone())
 }
 
-pub fn get_models(conf: &lib::Config) -> Result<Vec<String>> {
+pub fn get_models(client: &OllamaClient, conf: &lib::Config) -> Result<Vec<String>> {
     let msg = format!(
         "Attempting to get available models from {}:{}",
         conf.host, conf.port
     );
     lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
-    let full_url = format!("http://{}:{}/api/tags", conf.host, conf.port);
-    let resp: ModelResponse = reqwest::blocking::get(full_url)?.json()?;
-    let models = resp.models.into_iter().map(|m| m.name).collect();
+    let models: Vec<String> = client.tags()?.into_iter().map(|m| m.name).collect();
     let msg = format!(
         "Available models at {}:{} : {:?}",
         conf.host, conf.port, models
@@ -195,6 +665,292 @@ pub fn get_models(conf: &lib::Config) -> Result<Vec<String>> {
     Ok(models)
 }
 
+/// Checks a structured answer is JSON and, when `format` is a schema,
+/// that it validates against it
+pub fn check_format(answer: &str, format: &serde_json::Value) -> Result<()> {
+    let value: serde_json::Value =
+        serde_json::from_str(answer).map_err(|e| anyhow!("Answer is not valid JSON -> {}", e))?;
+    if !format.is_object() {
+        return Ok(());
+    }
+    let schema = JSONSchema::compile(format).map_err(|e| anyhow!("Invalid schema -> {}", e))?;
+    if let Err(errors) = schema.validate(&value) {
+        let errors: Vec<String> = errors
+            .map(|e| match e.instance_path.to_string().as_str() {
+                "" => e.to_string(),
+                path => format!("{}: {}", path, e),
+            })
+            .collect();
+        bail!("Answer does not match the schema -> {}", errors.join("; "));
+    }
+    Ok(())
+}
+
+/// Prints the models on the server (`-L`) as a table, sorted by `sort`
+/// ("name", "size" or "modified") and optionally limited to a family or to
+/// names matching `pattern` (a glob, or a substring without wildcards).
+/// The configured model is marked with "*".
+pub fn list_models(
+    sort: &str,
+    family: Option<&str>,
+    pattern: Option<&str>,
+    client: &OllamaClient,
+    conf: &lib::Config,
+) -> Result<()> {
+    let pattern = match pattern {
+        Some(p) if p.contains(['*', '?', '[']) => Some(glob::Pattern::new(p)?),
+        Some(p) => Some(glob::Pattern::new(&format!(
+            "*{}*",
+            glob::Pattern::escape(p)
+        ))?),
+        None => None,
+    };
+    let models = client.tags()?;
+    let names: Vec<String> = models.iter().map(|m| m.name.clone()).collect();
+    let current = resolve_model(&conf.model, &names).ok();
+    let mut models: Vec<Model> = models
+        .into_iter()
+        .filter(|m| {
+            family.is_none_or(|f| {
+                m.details.family.eq_ignore_ascii_case(f)
+                    || m.details
+                        .families
+                        .iter()
+                        .flatten()
+                        .any(|fam| fam.eq_ignore_ascii_case(f))
+            })
+        })
+        .filter(|m| pattern.as_ref().is_none_or(|p| p.matches(&m.name)))
+        .collect();
+    match sort {
+        "size" => models.sort_by_key(|m| std::cmp::Reverse(m.size)),
+        "modified" => models.sort_by_key(|m| std::cmp::Reverse(modified(m))),
+        _ => models.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+    match lib::output() {
+        lib::Output::Json => lib::print_json(&serde_json::json!({ "models": models })),
+        lib::Output::Ndjson => models.iter().for_each(lib::print_json),
+        lib::Output::Plain if models.is_empty() => {
+            lib::fmt_print("No models found", lib::ContentType::Info, conf.color)
+        }
+        lib::Output::Plain => {
+            let rows: Vec<[String; 6]> = models
+                .iter()
+                .map(|m| {
+                    let name = match (current.as_ref() == Some(&m.name), conf.color) {
+                        (true, true) => format!("* {}", m.name).green().to_string(),
+                        (true, false) => format!("* {}", m.name),
+                        (false, _) => format!("  {}", m.name),
+                    };
+                    [
+                        name,
+                        m.details.family.clone(),
+                        m.details.parameter_size.clone(),
+                        m.details.quantization_level.clone(),
+                        human_size(m.size),
+                        modified(m).map_or("-".to_string(), ago),
+                    ]
+                })
+                .collect();
+            let header = ["  NAME", "FAMILY", "PARAMS", "QUANT", "SIZE", "MODIFIED"];
+            print_table(&header, &rows, conf.color);
+        }
+    }
+    Ok(())
+}
+
+/// Bytes in decimal units, as Ollama reports them (e.g. "4.7 GB")
+pub fn human_size(bytes: i64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Left-aligned columns with a header row (bold with color)
+pub fn print_table<const N: usize>(header: &[&str; N], rows: &[[String; N]], color: bool) {
+    let mut widths = header.map(measure_text_width);
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(measure_text_width(cell));
+        }
+    }
+    let line = |cells: Vec<String>| -> String {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(c, w)| format!("{}{}", c, " ".repeat(w - measure_text_width(c))))
+            .collect::<Vec<String>>()
+            .join("   ")
+            .trim_end()
+            .to_string()
+    };
+    let head = line(header.iter().map(|h| h.to_string()).collect());
+    match color {
+        true => println!("{}", head.bold()),
+        false => println!("{}", head),
+    }
+    for row in rows {
+        println!("{}", line(row.to_vec()));
+    }
+}
+
+fn modified(model: &Model) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(&model.modified_at)
+        .ok()
+        .map(|t| t.with_timezone(&Local))
+}
+
+// Age of a model, e.g. "3 days ago"
+fn ago(time: DateTime<Local>) -> String {
+    let age = Local::now() - time;
+    let (n, unit) = match age.num_minutes() {
+        m if m < 1 => return "Just now".to_string(),
+        m if m < 60 => (m, "minute"),
+        m if m < 60 * 24 => (age.num_hours(), "hour"),
+        m if m < 60 * 24 * 7 => (age.num_days(), "day"),
+        m if m < 60 * 24 * 30 => (age.num_weeks(), "week"),
+        m if m < 60 * 24 * 365 => (age.num_days() / 30, "month"),
+        _ => (age.num_days() / 365, "year"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+// Share of a loaded model in GPU memory, like `ollama ps`
+fn processor(size: i64, size_vram: i64) -> String {
+    if size_vram <= 0 {
+        return "100% CPU".to_string();
+    }
+    if size_vram >= size {
+        return "100% GPU".to_string();
+    }
+    let gpu = size_vram * 100 / size;
+    format!("{}%/{}% CPU/GPU", 100 - gpu, gpu)
+}
+
+// Time until a loaded model is unloaded
+fn until(expires_at: &str) -> String {
+    let Ok(expires) = DateTime::parse_from_rfc3339(expires_at) else {
+        return expires_at.to_string();
+    };
+    let left = expires.with_timezone(&Local) - Local::now();
+    match left.num_minutes() {
+        m if m < 0 => "Stopping".to_string(),
+        0 => "Less than a minute".to_string(),
+        m if m < 60 => format!("{} minutes", m),
+        m if m < 60 * 24 => format!("{} hours", m / 60),
+        m if m < 60 * 24 * 365 => format!("{} days", m / (60 * 24)),
+        _ => "Forever".to_string(),
+    }
+}
+
+// Progress update from a create or push
+fn print_status(status: &PullResponse, pb: &ProgressBar) -> Result<()> {
+    if lib::output() == lib::Output::Ndjson {
+        lib::print_json(status);
+    }
+    let mut msg = status.status.clone().unwrap_or_default();
+    if let (Some(total), Some(completed)) = (status.total, status.completed) {
+        if let Some(pct) = (completed * 100).checked_div(total) {
+            msg.push_str(&format!(" {}%", pct));
+        }
+    }
+    pb.set_message(msg);
+    Ok(())
+}
+
+fn finish_status(result: Result<PullResponse>, pb: ProgressBar, conf: &lib::Config) -> Result<()> {
+    let status = finish_request(result, pb, conf)?;
+    match lib::output() {
+        lib::Output::Json => lib::print_json(&status),
+        lib::Output::Ndjson => (),
+        lib::Output::Plain => lib::fmt_print("Done", lib::ContentType::Exit, conf.color),
+    }
+    Ok(())
+}
+
+fn request_options(conf: &lib::Config) -> Option<ModelOptions> {
+    Some(conf.effective_options()).filter(|o| !o.is_empty())
+}
+
+// Stops the spinner and printer once a streamed request returns
+fn finish_stream<T>(
+    result: Result<T>,
+    mut printer: StreamPrinter,
+    pb: ProgressBar,
+    conf: &lib::Config,
+) -> Result<T> {
+    match result {
+        Ok(chunk) => {
+            if !pb.is_finished() {
+                pb.finish_and_clear();
+            }
+            printer.finish()?;
+            Ok(chunk)
+        }
+        Err(e) => {
+            printer.finish()?;
+            finish_spinner_error(pb, conf.color);
+            Err(e)
+        }
+    }
+}
+
+// Stops the spinner once a non-streamed request returns
+fn finish_request<T>(result: Result<T>, pb: ProgressBar, conf: &lib::Config) -> Result<T> {
+    match result {
+        Ok(resp) => {
+            pb.finish_and_clear();
+            Ok(resp)
+        }
+        Err(e) => {
+            finish_spinner_error(pb, conf.color);
+            Err(e)
+        }
+    }
+}
+
+/// Prints the model, token counts and timings of an answer (verbose output)
+pub fn print_summary(resp: &GenerateResponse, conf: &lib::Config) -> Result<()> {
+    // Metrics are part of the answer object in the JSON output modes
+    if lib::output().is_json() {
+        return Ok(());
+    }
+    let model = resp.model.as_deref().unwrap_or("Unknown");
+    let prompt_eval_count = resp.prompt_eval_count.unwrap_or(0);
+    let eval_count = resp.eval_count.unwrap_or(0);
+    let total_duration: f64 = resp.total_duration.unwrap_or(0) as f64 / 1000000000.0;
+    let load_duration: f64 = resp.load_duration.unwrap_or(0) as f64 / 1000000000.0;
+    let msg = format!(
+        "Response generated from {}:{} -> [\"{}\",{},{},{}]",
+        conf.host, conf.port, model, prompt_eval_count, eval_count, total_duration
+    );
+    lib::log(lib::LogLevel::Debug, "ollama", &msg)?;
+    let mut info = format!(
+        "* Model: {}\n* Tokens in prompt: {}\n* Tokens in response: {}\n* Time taken: {:.3}s\n* Load time: {:.3}s",
+        model, prompt_eval_count, eval_count, total_duration, load_duration
+    );
+    let opts = conf.effective_options();
+    if !opts.is_empty() {
+        info.push_str(&format!("\n* Options: {}", opts));
+    }
+    // A structured answer keeps stdout to itself, e.g. for piping to jq
+    if conf.format.is_some() {
+        eprintln!("\nDone\n{}", info);
+        return Ok(());
+    }
+    lib::fmt_print("\nDone", lib::ContentType::Info, conf.color);
+    lib::fmt_print(&info, lib::ContentType::Info, conf.color);
+    Ok(())
+}
+
 fn start_spinner(color: bool) -> ProgressBar {
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(Duration::from_millis(120));
@@ -231,19 +987,205 @@ fn finish_spinner_error(pb: ProgressBar, color: bool) {
     pb.finish_with_message("Error");
 }
 
-fn get_postdata(hm: HashMap<String, String>) -> String {
-    let mut output = String::new();
-    output.push('{');
-    for (k, v) in hm.iter() {
-        if k == "context" || k == "stream" {
-            let segment = format!("\"{}\":{},", k, v);
-            output.push_str(&segment);
-        } else {
-            let segment = format!("\"{}\":\"{}\",", k, v);
-            output.push_str(&segment);
+// Prints streamed tokens as they arrive. With color enabled on a terminal, each
+// finished markdown block (paragraph or fenced code block) is cleared and
+// re-rendered through `lib::fmt_print` so highlighting matches non-streamed output.
+struct StreamPrinter {
+    output: lib::Output,
+    render: bool,
+    term: Term,
+    block: String,
+    in_fence: bool,
+    started: bool,
+}
+
+impl StreamPrinter {
+    fn new(color: bool) -> Self {
+        let term = Term::stdout();
+        StreamPrinter {
+            output: lib::output(),
+            render: color && term.is_term(),
+            term,
+            block: String::new(),
+            in_fence: false,
+            started: false,
+        }
+    }
+
+    fn push_token(&mut self, token: &str, pb: &ProgressBar) -> Result<()> {
+        if token.is_empty() {
+            return Ok(());
+        }
+        if !pb.is_finished() {
+            pb.finish_and_clear();
+        }
+        match self.output {
+            lib::Output::Plain => (),
+            // The full answer is printed once it is known to be complete
+            lib::Output::Json => return Ok(()),
+            lib::Output::Ndjson => {
+                lib::print_json(&serde_json::json!({"response": token, "done": false}));
+                return Ok(());
+            }
+        }
+        if !self.started {
+            self.started = true;
+            self.print_rule()?;
+        }
+        for piece in token.split_inclusive('\n') {
+            print!("{}", piece);
+            io::stdout().flush()?;
+            if !self.render {
+                continue;
+            }
+            self.block.push_str(piece);
+            if piece.ends_with('\n') && self.block_finished() {
+                self.render_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.started {
+            return Ok(());
+        }
+        if !self.render {
+            println!();
+            return Ok(());
         }
+        if !self.block.is_empty() {
+            if !self.block.ends_with('\n') {
+                println!();
+                self.block.push('\n');
+            }
+            self.render_block()?;
+        }
+        self.print_rule()
+    }
+
+    // Called once the block ends in a newline; checks the line just completed.
+    fn block_finished(&mut self) -> bool {
+        let line = self.block[..self.block.len() - 1]
+            .rsplit('\n')
+            .next()
+            .unwrap_or("");
+        if line.trim_start().starts_with("```") {
+            self.in_fence = !self.in_fence;
+            return !self.in_fence;
+        }
+        !self.in_fence && line.trim().is_empty() && !self.block.trim().is_empty()
+    }
+
+    fn render_block(&mut self) -> Result<()> {
+        let block = std::mem::take(&mut self.block);
+        let (height, width) = self.term.size();
+        let rows: usize = block
+            .lines()
+            .map(|l| measure_text_width(l).div_ceil(width as usize).max(1))
+            .sum();
+        // Blocks taller than the screen cannot be cleared; leave them as streamed
+        if rows >= height as usize {
+            return Ok(());
+        }
+        self.term.clear_last_lines(rows)?;
+        lib::fmt_print(&block, lib::ContentType::AnswerBlock, true);
+        Ok(())
+    }
+
+    fn print_rule(&self) -> Result<()> {
+        if self.render {
+            let (_, width) = self.term.size();
+            println!("{}", "─".repeat(width as usize).dimmed());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Streams text line by line the way `push_token` does and returns the
+    // blocks that would be re-rendered, plus what is left pending
+    fn blocks(text: &str) -> (Vec<String>, String) {
+        let mut printer = StreamPrinter::new(false);
+        let mut finished = vec![];
+        for line in text.split_inclusive('\n') {
+            printer.block.push_str(line);
+            if line.ends_with('\n') && printer.block_finished() {
+                finished.push(std::mem::take(&mut printer.block));
+            }
+        }
+        (finished, printer.block)
+    }
+
+    #[test]
+    fn blocks_end_at_blank_lines() {
+        let (finished, pending) = blocks("One\ntwo.\n\nThree\n\n\nFour");
+        assert_eq!(finished, ["One\ntwo.\n\n", "Three\n\n"]);
+        // Extra blank lines wait for the next block
+        assert_eq!(pending, "\nFour");
+        let (finished, pending) = blocks("\n  \nText");
+        assert!(finished.is_empty());
+        assert_eq!(pending, "\n  \nText");
+    }
+
+    #[test]
+    fn fenced_code_is_one_block() {
+        let text = "Run:\n```sh\necho a\n\necho b\n  ```\nDone\n";
+        let (finished, pending) = blocks(text);
+        assert_eq!(finished, ["Run:\n```sh\necho a\n\necho b\n  ```\n"]);
+        assert_eq!(pending, "Done\n");
+
+        let (finished, pending) = blocks("```\nfn a() {}\n\n");
+        assert!(finished.is_empty());
+        assert_eq!(pending, "```\nfn a() {}\n\n");
+    }
+
+    #[test]
+    fn check_format_accepts_json_and_matching_answers() {
+        let json = serde_json::json!("json");
+        assert!(check_format(r#"{"name": "r2"}"#, &json).is_ok());
+        assert!(check_format("[1, 2]", &json).is_ok());
+        let err = check_format("Sure! {\"name\": 1}", &json).unwrap_err();
+        assert!(err.to_string().starts_with("Answer is not valid JSON"));
+
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+            "required": ["name"]
+        });
+        assert!(check_format(r#"{"name": "r2", "age": 3}"#, &schema).is_ok());
+        let err = check_format(r#"{"name": 2}"#, &schema).unwrap_err();
+        assert!(err.to_string().contains("/name"));
+        assert!(check_format(r#"{"age": 3}"#, &schema).is_err());
+        assert!(check_format("not json", &schema).is_err());
+    }
+
+    #[test]
+    fn resolves_exact_and_latest_only() {
+        let avail = models(&["llama3:latest", "llama3:70b", "codellama3:latest"]);
+        assert_eq!(resolve_model("llama3", &avail).unwrap(), "llama3:latest");
+        assert_eq!(resolve_model("llama3:70b", &avail).unwrap(), "llama3:70b");
+        assert!(resolve_model("llama", &avail).is_err());
+        assert!(resolve_model("llama3:8b", &avail).is_err());
+
+        let registry = models(&["localhost:5000/team/reviewer:latest"]);
+        let resolved = resolve_model("localhost:5000/team/reviewer", &registry).unwrap();
+        assert_eq!(resolved, "localhost:5000/team/reviewer:latest");
+    }
+
+    #[test]
+    fn untagged_name_without_latest_names_the_tags() {
+        let avail = models(&["llama3:70b", "llama3:8b", "mistral:7b"]);
+        let err = resolve_model("llama3", &avail).unwrap_err().to_string();
+        assert!(err.contains("ambiguous") && err.contains("llama3:70b, llama3:8b"));
+        let err = resolve_model("mistral", &avail).unwrap_err().to_string();
+        assert!(err.contains("did you mean \"mistral:7b\""));
     }
-    output.pop();
-    output.push('}');
-    output
 }
@@ -0,0 +1,735 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use lib::client::{GenerateResponse, OllamaClient};
+
+use crate::{attach, db, input, kb, ollama};
+
+const HELP: &str = "Commands:
+  /model [MODEL]   Show or switch the model (\"/model ?\" picks from a list)
+  /system [TEXT]   Show or set the system prompt (\"/system reset\" removes it)
+  /persona [NAME]  List personas or use one as the system prompt
+  /attach [PATH]   Attach files, directories or globs to the next prompt
+                   (no path lists pending attachments, \"/attach reset\" drops them;
+                   quote paths with spaces: /attach \"my notes.md\")
+  /image [PATH]    Attach PNG/JPEG images to the next prompt (vision models)
+                   (no path lists pending images, \"/image reset\" drops them;
+                   quote paths with spaces)
+  /history         Print the conversation so far
+  /retry [edit]    Ask the last prompt again, replacing the last answer
+                   (\"edit\" revises the prompt in $EDITOR first)
+  /edit            Compose a prompt in $VISUAL/$EDITOR
+  /clear           Start a new conversation (keeps the system prompt)
+  /save            Save the conversation now
+  /verbose         Toggle verbose output
+  /exit            End the session
+Start a prompt with \"//\" to send a literal \"/\".
+Alt+Enter inserts a newline; text opened with \"\"\" continues until a closing \"\"\".
+Ctrl-R searches prompt history, Ctrl-D ends the session.";
+
+#[derive(Default)]
+pub struct Session {
+    pub conversation: Vec<db::Chat>,
+    pub context: Option<Vec<i64>>,
+    pub system: Option<String>,
+    // Files prepended to the next prompt
+    pub attachments: Vec<attach::Attachment>,
+    // Image paths sent with the next prompt
+    pub images: Vec<String>,
+    prev_context: Option<Vec<i64>>,
+    // Row the conversation was restored from or last saved to
+    id: Option<i64>,
+    saved: bool,
+}
+
+enum Command {
+    Prompt(String),
+    Model(Option<String>),
+    System(Option<String>),
+    Persona(Option<String>),
+    Attach(Option<String>),
+    Image(Option<String>),
+    History,
+    Retry { edit: bool },
+    Edit,
+    Clear,
+    Save,
+    Verbose,
+    Help,
+    Exit,
+    Unknown(String),
+}
+
+impl Session {
+    pub fn new(
+        conversation: Vec<db::Chat>,
+        context: Option<Vec<i64>>,
+        system: Option<String>,
+    ) -> Self {
+        Session {
+            conversation,
+            context,
+            system,
+            ..Default::default()
+        }
+    }
+
+    /// Sends prompt, with any pending attachments, along with the conversation
+    /// so far and records the answer
+    pub fn ask(&mut self, prompt: String, client: &OllamaClient, conf: &lib::Config) -> Result<()> {
+        let sources = match conf.kb.is_empty() {
+            true => vec![],
+            false => kb::retrieve(&prompt, &conf.kb, conf.kb_top_k, client, conf)?,
+        };
+        kb::print_sources(&sources, conf.color);
+        let chat = db::Chat {
+            role: "user".to_string(),
+            content: attach::compose(&prompt, &self.attachments),
+            attachments: self
+                .attachments
+                .iter()
+                .map(|a| a.path.clone())
+                .chain(sources.iter().map(kb::Source::label))
+                .collect(),
+            images: self.images.clone(),
+            sources,
+            prompt: Some(prompt),
+            ..Default::default()
+        };
+        self.send(chat, client, conf)?;
+        self.attachments.clear();
+        self.images.clear();
+        Ok(())
+    }
+
+    // With a structured format, invalid answers are dropped and the prompt is
+    // sent again with the validation error, up to `conf.format_retries` times
+    fn send(&mut self, chat: db::Chat, client: &OllamaClient, conf: &lib::Config) -> Result<()> {
+        let Some(format) = &conf.format else {
+            let resp = self.exchange(chat, client, conf)?;
+            if lib::output().is_json() {
+                lib::print_json(&resp);
+            }
+            return Ok(());
+        };
+        let resp = retry_format(conf.format_retries, conf.color, |rejection| {
+            let mut attempt = chat.clone();
+            if let Some(err) = rejection {
+                attempt.content = format!(
+                    "{}\n\nYour previous answer was rejected: {}. Reply with valid JSON only.",
+                    chat.content, err
+                );
+            }
+            let resp = self.exchange(attempt, client, conf)?;
+            let answer = resp.response.as_deref().unwrap_or_default();
+            if let Err(err) = ollama::check_format(answer, format) {
+                self.conversation.truncate(self.conversation.len() - 2);
+                if !conf.chat {
+                    self.context = self.prev_context.take();
+                }
+                return Ok(Err(err));
+            }
+            // The rejection is kept out of the saved prompt
+            if rejection.is_some() {
+                let prompt = self.conversation.len() - 2;
+                self.conversation[prompt].content = chat.content.clone();
+            }
+            Ok(Ok(resp))
+        })?;
+        match lib::output().is_json() {
+            true => lib::print_json(&resp),
+            false => {
+                let answer = resp.response.as_deref().unwrap_or_default();
+                lib::fmt_print(answer, lib::ContentType::Json, conf.color);
+            }
+        }
+        if conf.verbose {
+            ollama::print_summary(&resp, conf)?;
+        }
+        Ok(())
+    }
+
+    // Sends one prompt and records the answer. The returned response holds the
+    // answer and metrics; its context has been moved into the session.
+    fn exchange(
+        &mut self,
+        mut chat: db::Chat,
+        client: &OllamaClient,
+        conf: &lib::Config,
+    ) -> Result<GenerateResponse> {
+        let prompt = chat.request_content();
+        let images = chat.images.clone();
+        chat.timestamp = Some(Local::now().timestamp_millis());
+        self.conversation.push(chat);
+        let system = self.system.as_deref();
+        let resp = match conf.chat {
+            true => ollama::chat(&self.conversation, system, client, conf),
+            false => ollama::gen(prompt, &images, self.context.clone(), system, client, conf).map(
+                |mut resp| {
+                    self.prev_context = std::mem::replace(&mut self.context, resp.context.take());
+                    resp
+                },
+            ),
+        };
+        match resp {
+            Ok(resp) => {
+                self.conversation.push(db::Chat {
+                    role: "assistant".to_string(),
+                    content: resp.response.clone().unwrap_or_default(),
+                    timestamp: Some(Local::now().timestamp_millis()),
+                    model: Some(resp.model.clone().unwrap_or(conf.model.clone())),
+                    prompt_eval_count: resp.prompt_eval_count,
+                    eval_count: resp.eval_count,
+                    eval_duration: resp.eval_duration,
+                    ..Default::default()
+                });
+                self.saved = false;
+                Ok(resp)
+            }
+            Err(e) => {
+                self.conversation.pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Session continuing a saved conversation; saving updates it in place
+    pub fn restored(restored: db::Restored, system: Option<String>) -> Self {
+        Session {
+            id: Some(restored.id),
+            saved: true,
+            ..Session::new(restored.conversation, restored.context, system)
+        }
+    }
+
+    pub fn save(&mut self, conf: &lib::Config) -> Result<()> {
+        self.id = db::save_conversation(
+            self.id,
+            &self.conversation,
+            self.context.clone(),
+            self.system.as_deref(),
+            conf,
+        )?;
+        self.saved = true;
+        Ok(())
+    }
+
+    /// True if there is something new to save since the last `/save`
+    pub fn unsaved(&self) -> bool {
+        !self.saved && !self.conversation.is_empty()
+    }
+
+    // Last prompt as typed; restored prompts keep their attachments
+    fn last_prompt(&self) -> Option<&str> {
+        self.conversation
+            .iter()
+            .rfind(|c| c.role == "user")
+            .map(|c| c.prompt.as_deref().unwrap_or(&c.content))
+    }
+
+    // Replaces the last exchange, optionally with a revised prompt
+    fn retry(
+        &mut self,
+        revised: Option<String>,
+        client: &OllamaClient,
+        conf: &lib::Config,
+    ) -> Result<()> {
+        let last_user = self.conversation.iter().rposition(|c| c.role == "user");
+        let Some(idx) = last_user else {
+            bail!("Nothing to retry");
+        };
+        let mut chat = self.conversation[idx].clone();
+        if let Some(prompt) = revised {
+            // Attachments stay ahead of the revised prompt
+            let typed = chat.prompt.as_deref().unwrap_or(&chat.content);
+            let attached = chat.content.strip_suffix(typed).unwrap_or_default();
+            chat.content = format!("{}{}", attached, prompt);
+            chat.prompt = Some(prompt);
+        }
+        let removed = self.conversation.split_off(idx);
+        let context = self.context.clone();
+        if !conf.chat {
+            self.context = self.prev_context.take();
+        }
+        if let Err(e) = self.send(chat, client, conf) {
+            self.conversation.extend(removed);
+            self.context = context;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Interactive loop: plain text is sent as a prompt, "/" starts a command.
+/// Returns when the user exits.
+pub fn run(session: &mut Session, client: &OllamaClient, conf: &mut lib::Config) -> Result<()> {
+    let mut editor = input::LineEditor::new(conf.color)?;
+    lib::fmt_print(
+        "Type /help for commands",
+        lib::ContentType::Info,
+        conf.color,
+    );
+    loop {
+        let Some(line) = editor.read("Ask R2")? else {
+            return Ok(());
+        };
+        // A failed command is reported and the session goes on
+        match handle(parse(&line), session, client, conf) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => print_error(&e.to_string(), "repl", conf.color),
+        }
+    }
+}
+
+// Runs one prompt or command; true when the session should end
+fn handle(
+    command: Command,
+    session: &mut Session,
+    client: &OllamaClient,
+    conf: &mut lib::Config,
+) -> Result<bool> {
+    match command {
+        Command::Prompt(prompt) => {
+            if prompt.trim().is_empty() {
+                return Ok(false);
+            }
+            if let Err(e) = session.ask(prompt, client, conf) {
+                let err_msg = format!(
+                    "Failed to generate response from {}:{} -> {}",
+                    conf.host, conf.port, e
+                );
+                print_error(&err_msg, "ollama", conf.color);
+            }
+        }
+        Command::Model(None) => {
+            let models = list_models(client, conf)?;
+            let msg = format!(
+                "Selected model: \"{}\"\nAvailable models: {:?}",
+                conf.model, models
+            );
+            lib::fmt_print(&msg, lib::ContentType::Info, conf.color);
+        }
+        Command::Model(Some(model)) => {
+            let models = list_models(client, conf)?;
+            let resolved = match model.as_str() {
+                "?" => ollama::pick_model(&models, &conf.model, conf.color),
+                name => ollama::resolve_model(name, &models),
+            };
+            conf.model = match resolved {
+                Ok(m) => m,
+                Err(e) => {
+                    print_error(&e.to_string(), "ollama", conf.color);
+                    return Ok(false);
+                }
+            };
+            // Context tokens belong to the previous model's tokenizer
+            if !conf.chat && session.context.take().is_some() {
+                session.prev_context = None;
+                lib::fmt_print(
+                    "Context cleared (generate mode cannot carry context across models)",
+                    lib::ContentType::Info,
+                    conf.color,
+                );
+            }
+            let msg = format!("Switched to model \"{}\"", conf.model);
+            lib::log(lib::LogLevel::Info, "repl", &msg)?;
+            lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+        }
+        Command::System(None) => {
+            let msg = match &session.system {
+                Some(s) => format!("System prompt: {}", s),
+                None => "No system prompt set".to_string(),
+            };
+            lib::fmt_print(&msg, lib::ContentType::Info, conf.color);
+        }
+        Command::System(Some(system)) => {
+            if system == "reset" {
+                session.system = None;
+                lib::fmt_print("System prompt removed", lib::ContentType::Exit, conf.color);
+            } else {
+                session.system = Some(system);
+                lib::fmt_print("System prompt set", lib::ContentType::Exit, conf.color);
+            }
+        }
+        Command::Persona(None) => {
+            let personas = lib::list_personas()?;
+            let msg = match personas.is_empty() {
+                true => format!(
+                    "No personas found. Add prompt files (e.g. reviewer.md) to {}",
+                    lib::get_project_file(lib::ProjFiles::Personas)?
+                ),
+                false => format!("Personas: {}", personas.join(", ")),
+            };
+            lib::fmt_print(&msg, lib::ContentType::Info, conf.color);
+        }
+        Command::Persona(Some(name)) => match lib::get_persona(&name) {
+            Ok(system) => {
+                session.system = Some(system);
+                let msg = format!("Using persona \"{}\"", name);
+                lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+            }
+            Err(e) => print_error(&e.to_string(), "repl", conf.color),
+        },
+        Command::Attach(None) => {
+            let msg = match session.attachments.is_empty() {
+                true => "No pending attachments".to_string(),
+                false => format!(
+                    "Attached to next prompt: {}",
+                    attachment_names(&session.attachments)
+                ),
+            };
+            lib::fmt_print(&msg, lib::ContentType::Info, conf.color);
+        }
+        Command::Attach(Some(arg)) => {
+            if arg == "reset" {
+                session.attachments.clear();
+                lib::fmt_print("Attachments removed", lib::ContentType::Exit, conf.color);
+                return Ok(false);
+            }
+            let mut patterns: Vec<String> =
+                session.attachments.iter().map(|a| a.path.clone()).collect();
+            patterns.extend(split_args(&arg)?);
+            match attach::collect(&patterns) {
+                Ok((attachments, skipped)) => {
+                    session.attachments = attachments;
+                    print_skipped(&skipped, conf.color);
+                    let msg = format!(
+                        "Attached to next prompt: {}",
+                        attachment_names(&session.attachments)
+                    );
+                    lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+                }
+                Err(e) => print_error(&e.to_string(), "attach", conf.color),
+            }
+        }
+        Command::Image(None) => {
+            let msg = match session.images.is_empty() {
+                true => "No pending images".to_string(),
+                false => format!("Images for next prompt: {}", session.images.join(", ")),
+            };
+            lib::fmt_print(&msg, lib::ContentType::Info, conf.color);
+        }
+        Command::Image(Some(arg)) => {
+            if arg == "reset" {
+                session.images.clear();
+                lib::fmt_print("Images removed", lib::ContentType::Exit, conf.color);
+                return Ok(false);
+            }
+            let paths = split_args(&arg)?;
+            match attach::check_images(&paths) {
+                Ok(paths) => {
+                    session.images.extend(paths);
+                    let msg = format!("Images for next prompt: {}", session.images.join(", "));
+                    lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+                }
+                Err(e) => print_error(&e.to_string(), "attach", conf.color),
+            }
+        }
+        Command::History => {
+            if session.conversation.is_empty() {
+                lib::fmt_print("Conversation is empty", lib::ContentType::Info, conf.color);
+            }
+            db::print_conversation(&session.conversation, conf.color);
+        }
+        Command::Retry { edit } => {
+            let revised = match edit {
+                true => match session.last_prompt() {
+                    Some(last) => match compose(last, conf.color) {
+                        Some(p) => Some(p),
+                        None => return Ok(false),
+                    },
+                    None => None,
+                },
+                false => None,
+            };
+            if let Err(e) = session.retry(revised, client, conf) {
+                let err_msg = format!("Failed to retry -> {}", e);
+                print_error(&err_msg, "ollama", conf.color);
+            }
+        }
+        Command::Edit => {
+            let Some(prompt) = compose("", conf.color) else {
+                return Ok(false);
+            };
+            if let Err(e) = session.ask(prompt, client, conf) {
+                let err_msg = format!(
+                    "Failed to generate response from {}:{} -> {}",
+                    conf.host, conf.port, e
+                );
+                print_error(&err_msg, "ollama", conf.color);
+            }
+        }
+        Command::Clear => {
+            session.conversation.clear();
+            session.context = None;
+            session.prev_context = None;
+            // What follows is a new conversation
+            session.id = None;
+            session.saved = false;
+            lib::fmt_print("Conversation cleared", lib::ContentType::Exit, conf.color);
+        }
+        Command::Save => match session.save(conf) {
+            Ok(_) => lib::fmt_print("Conversation saved", lib::ContentType::Exit, conf.color),
+            Err(e) => {
+                let err_msg = format!("Failed to save conversation -> {}", e);
+                print_error(&err_msg, "db", conf.color);
+            }
+        },
+        Command::Verbose => {
+            conf.verbose = !conf.verbose;
+            let msg = format!(
+                "Verbose output {}",
+                if conf.verbose { "enabled" } else { "disabled" }
+            );
+            lib::fmt_print(&msg, lib::ContentType::Exit, conf.color);
+        }
+        Command::Help => lib::fmt_print(HELP, lib::ContentType::Info, conf.color),
+        Command::Exit => return Ok(true),
+        Command::Unknown(cmd) => {
+            let msg = format!("Unknown command \"{}\". Type /help for commands", cmd);
+            lib::fmt_print(&msg, lib::ContentType::Error, conf.color);
+        }
+    }
+    Ok(false)
+}
+
+fn parse(input: &str) -> Command {
+    let trimmed = input.trim();
+    if let Some(literal) = trimmed.strip_prefix("//") {
+        return Command::Prompt(format!("/{}", literal));
+    }
+    let Some(cmd) = trimmed.strip_prefix('/') else {
+        return Command::Prompt(input.to_string());
+    };
+    let (name, arg) = match cmd.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, Some(arg.trim().to_string())),
+        None => (cmd, None),
+    };
+    let arg = arg.filter(|a| !a.is_empty());
+    match name {
+        "model" => Command::Model(arg),
+        "system" => Command::System(arg),
+        "persona" => Command::Persona(arg),
+        "attach" => Command::Attach(arg),
+        "image" => Command::Image(arg),
+        "history" => Command::History,
+        "retry" => match arg.as_deref() {
+            None => Command::Retry { edit: false },
+            Some("edit") => Command::Retry { edit: true },
+            Some(other) => Command::Unknown(format!("/retry {}", other)),
+        },
+        "edit" => Command::Edit,
+        "clear" => Command::Clear,
+        "save" => Command::Save,
+        "verbose" => Command::Verbose,
+        "help" => Command::Help,
+        "exit" | "quit" => Command::Exit,
+        _ => Command::Unknown(format!("/{}", name)),
+    }
+}
+
+// Command arguments split on whitespace, except inside "double" or 'single'
+// quotes, so paths with spaces can be given
+fn split_args(arg: &str) -> Result<Vec<String>> {
+    let mut args: Vec<String> = vec![];
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in arg.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        bail!("Missing closing {} quote in: {}", q, arg);
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+// Prompt from $VISUAL/$EDITOR; None if cancelled or the editor failed
+fn compose(initial: &str, color: bool) -> Option<String> {
+    match input::compose_in_editor(initial) {
+        Ok(Some(prompt)) => Some(prompt),
+        Ok(None) => {
+            lib::fmt_print("Empty prompt, nothing sent", lib::ContentType::Info, color);
+            None
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to compose prompt in editor -> {}", e);
+            print_error(&err_msg, "input", color);
+            None
+        }
+    }
+}
+
+/// Lists files left out of a directory or glob attachment
+pub fn print_skipped(skipped: &[String], color: bool) {
+    if !skipped.is_empty() {
+        let msg = format!("Skipped: {}", skipped.join(", "));
+        lib::fmt_print(&msg, lib::ContentType::Info, color);
+    }
+}
+
+fn attachment_names(attachments: &[attach::Attachment]) -> String {
+    attachments
+        .iter()
+        .map(|a| a.path.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+// Makes up to `retries` + 1 attempts at a structured answer. `attempt` gets the
+// error that rejected the previous answer, and returns Ok(Err(e)) for an answer
+// rejected with e; other errors end the attempts at once
+fn retry_format<T>(
+    retries: u32,
+    color: bool,
+    mut attempt: impl FnMut(Option<&anyhow::Error>) -> Result<Result<T>>,
+) -> Result<T> {
+    let mut rejection: Option<anyhow::Error> = None;
+    for retry in 0..=retries {
+        let err = match attempt(rejection.as_ref())? {
+            Ok(answer) => return Ok(answer),
+            Err(err) => err,
+        };
+        if retry == retries {
+            bail!("No valid answer after {} attempts -> {}", retry + 1, err);
+        }
+        let msg = format!("{}, asking again ({}/{})", err, retry + 1, retries);
+        print_error(&msg, "repl", color);
+        rejection = Some(err);
+    }
+    Err(anyhow!("No valid answer"))
+}
+
+fn list_models(client: &OllamaClient, conf: &lib::Config) -> Result<Vec<String>> {
+    ollama::get_models(client, conf).map_err(|e| {
+        anyhow!(
+            "Failed to list models on {}:{} -> {}",
+            conf.host,
+            conf.port,
+            e
+        )
+    })
+}
+
+fn print_error(msg: &str, descriptor: &str, color: bool) {
+    let _ = lib::log(lib::LogLevel::Error, descriptor, msg);
+    lib::fmt_print(msg, lib::ContentType::Error, color);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers in turn: None is a rejected answer, Some(n) a valid one
+    fn attempts(answers: &[Option<u32>], retries: u32) -> (Result<u32>, Vec<Option<String>>) {
+        let mut rejections: Vec<Option<String>> = vec![];
+        let mut answers = answers.iter();
+        let result = retry_format(retries, false, |rejection| {
+            rejections.push(rejection.map(|e| e.to_string()));
+            match answers.next() {
+                Some(Some(n)) => Ok(Ok(*n)),
+                Some(None) => Ok(Err(anyhow!("rejected {}", rejections.len()))),
+                None => Err(anyhow!("no more answers")),
+            }
+        });
+        (result, rejections)
+    }
+
+    #[test]
+    fn args_split_outside_quotes() {
+        let split = |arg: &str| split_args(arg).unwrap();
+        assert_eq!(split("a.rs  src/"), ["a.rs", "src/"]);
+        assert_eq!(
+            split(r#""my notes.md" 'it''s here/x' b"c d"e"#),
+            ["my notes.md", "its here/x", "bc de"]
+        );
+        assert_eq!(split(r#"'' 'a"b'"#), ["", "a\"b"]);
+        assert!(split_args("\"my notes.md").is_err());
+    }
+
+    #[test]
+    fn commands_and_prompts_are_parsed() {
+        assert!(matches!(parse("  why? "), Command::Prompt(p) if p == "  why? "));
+        assert!(matches!(parse("a /b"), Command::Prompt(p) if p == "a /b"));
+        // A doubled slash sends the rest as a prompt
+        assert!(matches!(parse("//etc/hosts?"), Command::Prompt(p) if p == "/etc/hosts?"));
+        assert!(matches!(parse(" // x"), Command::Prompt(p) if p == "/ x"));
+
+        assert!(matches!(parse("/model"), Command::Model(None)));
+        assert!(matches!(parse("/model   "), Command::Model(None)));
+        assert!(matches!(parse("/model  llama3 "), Command::Model(Some(m)) if m == "llama3"));
+        assert!(matches!(parse("/system be brief"), Command::System(Some(s)) if s == "be brief"));
+        assert!(
+            matches!(parse("/attach \"a b.md\""), Command::Attach(Some(a)) if a == "\"a b.md\"")
+        );
+        assert!(matches!(parse("/retry"), Command::Retry { edit: false }));
+        assert!(matches!(
+            parse("/retry edit"),
+            Command::Retry { edit: true }
+        ));
+        assert!(matches!(parse("/retry x"), Command::Unknown(u) if u == "/retry x"));
+        assert!(matches!(parse("/quit"), Command::Exit));
+        assert!(matches!(parse("/exit now"), Command::Exit));
+        assert!(matches!(parse("/Model"), Command::Unknown(u) if u == "/Model"));
+        assert!(matches!(parse("/"), Command::Unknown(u) if u == "/"));
+    }
+
+    #[test]
+    fn last_prompt_is_as_typed() {
+        let attachments = [attach::Attachment {
+            path: "a.rs".to_string(),
+            content: "fn a() {}".to_string(),
+        }];
+        let chat = |role: &str, content: String, prompt: Option<&str>| db::Chat {
+            role: role.to_string(),
+            content,
+            prompt: prompt.map(str::to_string),
+            ..Default::default()
+        };
+        let typed = chat("user", attach::compose("why?", &attachments), Some("why?"));
+        let answer = chat("assistant", "because".to_string(), None);
+        let session = Session::new(vec![typed, answer.clone()], None, None);
+        assert_eq!(session.last_prompt(), Some("why?"));
+        // Restored prompts were saved with their attachments
+        let restored = chat("user", "saved".to_string(), None);
+        let session = Session::new(vec![restored, answer], None, None);
+        assert_eq!(session.last_prompt(), Some("saved"));
+        assert_eq!(Session::default().last_prompt(), None);
+    }
+
+    #[test]
+    fn retries_until_an_answer_is_valid() {
+        let (result, rejections) = attempts(&[None, None, Some(7)], 2);
+        assert_eq!(result.unwrap(), 7);
+        let expected = [None, Some("rejected 1"), Some("rejected 2")];
+        assert_eq!(rejections, expected.map(|r| r.map(str::to_string)));
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let (result, rejections) = attempts(&[None, None, None, Some(7)], 2);
+        assert_eq!(rejections.len(), 3);
+        let err = result.unwrap_err().to_string();
+        assert_eq!(err, "No valid answer after 3 attempts -> rejected 3");
+
+        let (result, rejections) = attempts(&[None, Some(7)], 0);
+        assert_eq!(rejections.len(), 1);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn request_errors_are_not_retried() {
+        let (result, rejections) = attempts(&[], 5);
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(result.unwrap_err().to_string(), "no more answers");
+    }
+}